@@ -0,0 +1,138 @@
+// Finds the ACPI tables the firmware left in memory, starting from the RSDP in low memory
+use crate::memory::phys_to_virt;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use x86_64::PhysAddr;
+
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+// The segment of the Extended BIOS Data Area is stored here
+const EBDA_POINTER: u64 = 0x40E;
+const BIOS_AREA_START: u64 = 0xE0000;
+const BIOS_AREA_END: u64 = 0x100000;
+
+// Zero until init found the tables
+static ROOT_TABLE: AtomicU64 = AtomicU64::new(0);
+// The XSDT has 64 bit pointers, the RSDT 32 bit ones
+static EXTENDED: AtomicBool = AtomicBool::new(false);
+
+#[allow(dead_code)]
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+    // Only there since ACPI 2.0
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    reserved: [u8; 3],
+}
+
+// Every table starts with this
+#[allow(dead_code)]
+#[repr(C, packed)]
+#[derive(Clone, Copy)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
+}
+
+pub const HEADER_SIZE: u64 = core::mem::size_of::<SdtHeader>() as u64;
+
+// Reads from physical memory, which the bootloader mapped for us
+pub fn read<T: Copy>(address: PhysAddr) -> T {
+    unsafe { core::ptr::read_unaligned(phys_to_virt(address).as_ptr()) }
+}
+
+// All the bytes of a table add up to 0
+fn checksum_ok(address: PhysAddr, length: u64) -> bool {
+    let sum = (0..length).fold(0u8, |sum, i| sum.wrapping_add(read(address + i)));
+    sum == 0
+}
+
+fn search_rsdp(start: u64, end: u64) -> Option<PhysAddr> {
+    // It's always 16 byte aligned
+    (start..end)
+        .step_by(16)
+        .map(PhysAddr::new)
+        .find(|&address| read::<[u8; 8]>(address) == *RSDP_SIGNATURE && checksum_ok(address, 20))
+}
+
+fn find_rsdp() -> Option<PhysAddr> {
+    let ebda = (read::<u16>(PhysAddr::new(EBDA_POINTER)) as u64) << 4;
+    if ebda != 0 {
+        if let Some(address) = search_rsdp(ebda, ebda + 1024) {
+            return Some(address);
+        }
+    }
+    search_rsdp(BIOS_AREA_START, BIOS_AREA_END)
+}
+
+// Returns the ACPI revision, or None if there are no ACPI tables
+pub fn init() -> Option<u8> {
+    let address = find_rsdp()?;
+    let rsdp: Rsdp = read(address);
+
+    let (root, extended) =
+        if rsdp.revision >= 2 && rsdp.xsdt_address != 0 && checksum_ok(address, rsdp.length as u64)
+        {
+            (rsdp.xsdt_address, true)
+        } else {
+            (rsdp.rsdt_address as u64, false)
+        };
+
+    let header: SdtHeader = read(PhysAddr::new(root));
+    if !checksum_ok(PhysAddr::new(root), header.length as u64) {
+        return None;
+    }
+
+    ROOT_TABLE.store(root, Ordering::Relaxed);
+    EXTENDED.store(extended, Ordering::Relaxed);
+    Some(rsdp.revision)
+}
+
+// Calls f with the address and header of every table with a valid checksum
+pub fn for_each_table(mut f: impl FnMut(PhysAddr, &SdtHeader)) {
+    let root = ROOT_TABLE.load(Ordering::Relaxed);
+    if root == 0 {
+        return;
+    }
+    let root = PhysAddr::new(root);
+    let extended = EXTENDED.load(Ordering::Relaxed);
+
+    let header: SdtHeader = read(root);
+    let entry_size = if extended { 8 } else { 4 };
+    let entries = (header.length as u64 - HEADER_SIZE) / entry_size;
+    for i in 0..entries {
+        let entry = root + HEADER_SIZE + i * entry_size;
+        let address = if extended {
+            read::<u64>(entry)
+        } else {
+            read::<u32>(entry) as u64
+        };
+        let address = PhysAddr::new(address);
+        let table: SdtHeader = read(address);
+        if checksum_ok(address, table.length as u64) {
+            f(address, &table);
+        }
+    }
+}
+
+pub fn find_table(signature: &[u8; 4]) -> Option<PhysAddr> {
+    let mut found = None;
+    for_each_table(|address, header| {
+        if found.is_none() && header.signature == *signature {
+            found = Some(address);
+        }
+    });
+    found
+}
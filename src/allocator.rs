@@ -1,6 +1,9 @@
+use crate::{frames, memory};
 use alloc::alloc::{GlobalAlloc, Layout};
-use core::ptr::null_mut;
-use linked_list_allocator::LockedHeap;
+use core::ptr::{null_mut, NonNull};
+use linked_list_allocator::Heap;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
 use x86_64::{
     structures::paging::{
         mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB,
@@ -20,11 +23,212 @@ unsafe impl GlobalAlloc for Dummy {
     }
 }
 
+// Small allocations come from slabs of equal sized blocks, the rest from a linked list heap
+// The heap starts at HEAP_SIZE and maps more pages when it runs out, up to HEAP_MAX_SIZE
 #[global_allocator]
-static ALLOCATOR: LockedHeap = LockedHeap::empty();
+static ALLOCATOR: Allocator = Allocator(Mutex::new(KernelHeap::new()));
 
 pub const HEAP_START: usize = 0x_4444_4444_0000;
-pub const HEAP_SIZE: usize = 100 * 1024; // 100 KiB
+pub const HEAP_SIZE: usize = 2 * 1024 * 1024; // 2 MiB
+pub const HEAP_MAX_SIZE: usize = 256 * 1024 * 1024; // 256 MiB
+
+// Growing maps at least this much, so it doesn't happen for every allocation
+const GROW_SIZE: usize = 256 * 1024;
+
+// Powers of two, so blocks in a page are aligned to their size
+const SLAB_SIZES: [usize; 8] = [16, 32, 64, 128, 256, 512, 1024, 2048];
+// Slabs take memory from the linked list heap a page at a time, and never give it back
+const SLAB_PAGE: usize = 4096;
+
+#[derive(Clone, Copy)]
+struct Slab {
+    // Address of the first free block, each free block has the address of the next one
+    free: usize,
+    blocks: usize,
+    used: usize,
+}
+
+const EMPTY_SLAB: Slab = Slab {
+    free: 0,
+    blocks: 0,
+    used: 0,
+};
+
+struct KernelHeap {
+    slabs: [Slab; SLAB_SIZES.len()],
+    large: Heap,
+}
+
+pub struct Allocator(Mutex<KernelHeap>);
+
+// What meminfo shows, in bytes
+pub struct HeapStats {
+    // How much is mapped
+    pub size: usize,
+    pub used: usize,
+    pub slabs: [SlabStats; SLAB_SIZES.len()],
+}
+
+#[derive(Clone, Copy)]
+pub struct SlabStats {
+    pub size: usize,
+    pub blocks: usize,
+    pub used: usize,
+}
+
+// The smallest slab the layout fits in, None if it's too big for any of them
+fn slab_index(layout: &Layout) -> Option<usize> {
+    let size = core::cmp::max(layout.size(), layout.align());
+    SLAB_SIZES.iter().position(|&slab_size| slab_size >= size)
+}
+
+impl KernelHeap {
+    const fn new() -> Self {
+        KernelHeap {
+            slabs: [EMPTY_SLAB; SLAB_SIZES.len()],
+            large: Heap::empty(),
+        }
+    }
+
+    fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        match slab_index(&layout) {
+            Some(index) => self.allocate_block(index),
+            None => self.allocate_large(layout),
+        }
+    }
+
+    unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout) {
+        match slab_index(&layout) {
+            Some(index) => {
+                let slab = &mut self.slabs[index];
+                *(ptr as *mut usize) = slab.free;
+                slab.free = ptr as usize;
+                slab.used -= 1;
+            }
+            None => self.large.deallocate(NonNull::new_unchecked(ptr), layout),
+        }
+    }
+
+    fn allocate_block(&mut self, index: usize) -> *mut u8 {
+        if self.slabs[index].free == 0 {
+            let layout = Layout::from_size_align(SLAB_PAGE, SLAB_PAGE).unwrap();
+            let page = self.allocate_large(layout) as usize;
+            if page == 0 {
+                return null_mut();
+            }
+            let size = SLAB_SIZES[index];
+            let slab = &mut self.slabs[index];
+            for block in (page..page + SLAB_PAGE).step_by(size).rev() {
+                unsafe { *(block as *mut usize) = slab.free };
+                slab.free = block;
+            }
+            slab.blocks += SLAB_PAGE / size;
+        }
+
+        let slab = &mut self.slabs[index];
+        let block = slab.free;
+        slab.free = unsafe { *(block as *const usize) };
+        slab.used += 1;
+        block as *mut u8
+    }
+
+    fn allocate_large(&mut self, layout: Layout) -> *mut u8 {
+        // The free space already at the top counts too, so grow a bit at a time until it fits
+        // Once the new pages alone are big enough it would have fit, there's no point going on
+        let enough = layout.size() + layout.align();
+        let mut grown = 0;
+        loop {
+            if let Ok(ptr) = self.large.allocate_first_fit(layout) {
+                return ptr.as_ptr();
+            }
+            if grown >= enough {
+                return null_mut();
+            }
+            match self.grow() {
+                0 => return null_mut(),
+                mapped => grown += mapped,
+            }
+        }
+    }
+
+    // Maps up to GROW_SIZE more at the top of the heap, returns how much it mapped
+    fn grow(&mut self) -> usize {
+        let top = self.large.top();
+        let size = core::cmp::min(GROW_SIZE, HEAP_START + HEAP_MAX_SIZE - top);
+
+        // Waiting for the mapper could deadlock, whoever holds it might be the one allocating
+        let start = Page::containing_address(VirtAddr::new(top as u64));
+        let mapped = memory::try_with_mapper(|mapper| {
+            let mut mapped = 0;
+            for page in Page::range(start, start + (size / 4096) as u64) {
+                let frame = match frames::allocate() {
+                    Some(frame) => frame,
+                    None => break,
+                };
+                let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+                match unsafe { mapper.map_to(page, frame, flags, &mut frames::Allocator) } {
+                    Ok(flush) => flush.flush(),
+                    Err(_) => {
+                        unsafe { frames::deallocate(frame) };
+                        break;
+                    }
+                }
+                mapped += 4096;
+            }
+            mapped
+        })
+        .unwrap_or(0);
+
+        // Joins the free block at the top if there is one
+        if mapped != 0 {
+            unsafe { self.large.extend(mapped) };
+        }
+        mapped
+    }
+
+    fn stats(&self) -> HeapStats {
+        let mut slabs = [SlabStats {
+            size: 0,
+            blocks: 0,
+            used: 0,
+        }; SLAB_SIZES.len()];
+        let mut slab_pages = 0;
+        let mut slab_used = 0;
+        for ((stats, slab), &size) in slabs
+            .iter_mut()
+            .zip(self.slabs.iter())
+            .zip(SLAB_SIZES.iter())
+        {
+            *stats = SlabStats {
+                size,
+                blocks: slab.blocks,
+                used: slab.used,
+            };
+            slab_pages += slab.blocks * size / SLAB_PAGE;
+            slab_used += slab.used * size;
+        }
+        HeapStats {
+            size: self.large.size(),
+            used: self.large.used() - slab_pages * SLAB_PAGE + slab_used,
+            slabs,
+        }
+    }
+}
+
+// Interrupt handlers allocate too, the lock can't be held when one comes in
+unsafe impl GlobalAlloc for Allocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        without_interrupts(|| self.0.lock().allocate(layout))
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        without_interrupts(|| self.0.lock().deallocate(ptr, layout))
+    }
+}
+
+pub fn stats() -> HeapStats {
+    without_interrupts(|| ALLOCATOR.0.lock().stats())
+}
 
 pub fn init_heap(
     mapper: &mut impl Mapper<Size4KiB>,
@@ -47,7 +251,7 @@ pub fn init_heap(
     }
 
     unsafe {
-        ALLOCATOR.lock().init(HEAP_START, HEAP_SIZE);
+        ALLOCATOR.0.lock().large.init(HEAP_START, HEAP_SIZE);
     }
 
     Ok(())
@@ -0,0 +1,272 @@
+// ANSI/VT100 escape sequence parser
+// Only parses the sequences, the console decides what to do with them
+
+const MAX_PARAMS: usize = 8;
+
+// ANSI color numbers are in a different order than the VGA ones
+const ANSI_COLORS: [u8; 8] = [0, 4, 2, 6, 1, 5, 3, 7];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Ground,
+    Escape,
+    Csi,
+    // A sequence we don't understand, swallow it until its final byte
+    Ignore,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Action {
+    // A byte that isn't part of an escape sequence
+    Print(u8),
+    // ESC followed by a single byte, e.g. ESC 7
+    Escape(u8),
+    // ESC [ params final
+    Csi(Csi),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Csi {
+    params: [u16; MAX_PARAMS],
+    len: usize,
+    pub action: u8,
+}
+
+impl Csi {
+    pub fn params(&self) -> &[u16] {
+        &self.params[..self.len]
+    }
+
+    // Missing and zero parameters both mean "use the default"
+    pub fn param(&self, index: usize, default: u16) -> u16 {
+        match self.params().get(index) {
+            Some(&0) | None => default,
+            Some(&n) => n,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct Parser {
+    state: State,
+    params: [u16; MAX_PARAMS],
+    len: usize,
+}
+
+impl Parser {
+    pub const fn new() -> Parser {
+        Parser {
+            state: State::Ground,
+            params: [0; MAX_PARAMS],
+            len: 0,
+        }
+    }
+
+    pub fn advance(&mut self, byte: u8) -> Option<Action> {
+        match self.state {
+            State::Ground => {
+                if byte == 0x1b {
+                    self.state = State::Escape;
+                    None
+                } else {
+                    Some(Action::Print(byte))
+                }
+            }
+            State::Escape => match byte {
+                b'[' => {
+                    self.params = [0; MAX_PARAMS];
+                    self.len = 0;
+                    self.state = State::Csi;
+                    None
+                }
+                0x1b => None,
+                _ => {
+                    self.state = State::Ground;
+                    Some(Action::Escape(byte))
+                }
+            },
+            State::Csi => match byte {
+                b'0'..=b'9' => {
+                    if self.len == 0 {
+                        self.len = 1;
+                    }
+                    let param = &mut self.params[self.len - 1];
+                    *param = param
+                        .saturating_mul(10)
+                        .saturating_add((byte - b'0') as u16);
+                    None
+                }
+                b';' => {
+                    if self.len == 0 {
+                        self.len = 1;
+                    }
+                    if self.len == MAX_PARAMS {
+                        self.state = State::Ignore;
+                    } else {
+                        self.len += 1;
+                    }
+                    None
+                }
+                // Private sequences like ESC [ ? 25 l
+                b'?' | b'<' | b'=' | b'>' => {
+                    self.state = State::Ignore;
+                    None
+                }
+                0x40..=0x7e => {
+                    self.state = State::Ground;
+                    Some(Action::Csi(Csi {
+                        params: self.params,
+                        len: self.len,
+                        action: byte,
+                    }))
+                }
+                _ => {
+                    self.state = State::Ignore;
+                    None
+                }
+            },
+            State::Ignore => {
+                if let 0x40..=0x7e = byte {
+                    self.state = State::Ground;
+                }
+                None
+            }
+        }
+    }
+}
+
+// The table only swaps colors, so it also turns VGA colors (0-7) into ANSI ones
+pub fn ansi_color(vga_color: u8) -> u8 {
+    ANSI_COLORS[(vga_color & 7) as usize]
+}
+
+// Text colors as VGA color numbers (0-15)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Attributes {
+    pub foreground: u8,
+    pub background: u8,
+    pub bold: bool,
+}
+
+impl Attributes {
+    // White on black
+    pub const DEFAULT: Attributes = Attributes {
+        foreground: 15,
+        background: 0,
+        bold: false,
+    };
+
+    // Applies the parameters of an ESC [ ... m sequence
+    pub fn select_graphic_rendition(&mut self, params: &[u16]) {
+        // ESC [ m is the same as ESC [ 0 m
+        if params.is_empty() {
+            *self = Attributes::DEFAULT;
+        }
+
+        for &param in params {
+            match param {
+                0 => *self = Attributes::DEFAULT,
+                1 => {
+                    self.bold = true;
+                    self.foreground |= 8;
+                }
+                22 => {
+                    self.bold = false;
+                    self.foreground &= 7;
+                }
+                30..=37 => {
+                    let bright = if self.bold { 8 } else { 0 };
+                    self.foreground = ANSI_COLORS[(param - 30) as usize] | bright;
+                }
+                39 => self.foreground = Attributes::DEFAULT.foreground,
+                40..=47 => self.background = ANSI_COLORS[(param - 40) as usize],
+                49 => self.background = Attributes::DEFAULT.background,
+                90..=97 => self.foreground = ANSI_COLORS[(param - 90) as usize] | 8,
+                100..=107 => self.background = ANSI_COLORS[(param - 100) as usize] | 8,
+                _ => {}
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    fn feed(bytes: &[u8]) -> Vec<Action> {
+        let mut parser = Parser::new();
+        bytes
+            .iter()
+            .filter_map(|&byte| parser.advance(byte))
+            .collect()
+    }
+
+    fn csi(bytes: &[u8]) -> Csi {
+        match feed(bytes).as_slice() {
+            [Action::Csi(csi)] => *csi,
+            actions => panic!("expected one CSI sequence, got {:?}", actions),
+        }
+    }
+
+    #[test_case]
+    fn plain_text() {
+        assert_eq!(
+            feed(b"hi\n"),
+            [
+                Action::Print(b'h'),
+                Action::Print(b'i'),
+                Action::Print(b'\n')
+            ]
+        );
+    }
+
+    #[test_case]
+    fn csi_parameters() {
+        let sequence = csi(b"\x1b[1;31m");
+        assert_eq!(sequence.action, b'm');
+        assert_eq!(sequence.params(), [1, 31]);
+    }
+
+    #[test_case]
+    fn missing_parameters_use_the_default() {
+        let sequence = csi(b"\x1b[;5H");
+        assert_eq!(sequence.param(0, 1), 1);
+        assert_eq!(sequence.param(1, 1), 5);
+        assert_eq!(sequence.param(2, 1), 1);
+        assert!(csi(b"\x1b[H").params().is_empty());
+    }
+
+    #[test_case]
+    fn escape_then_text() {
+        assert_eq!(feed(b"\x1b7a"), [Action::Escape(b'7'), Action::Print(b'a')]);
+    }
+
+    #[test_case]
+    fn private_sequences_are_ignored() {
+        assert_eq!(feed(b"\x1b[?25la"), [Action::Print(b'a')]);
+    }
+
+    #[test_case]
+    fn too_many_parameters_are_ignored() {
+        assert_eq!(feed(b"\x1b[1;2;3;4;5;6;7;8;9ma"), [Action::Print(b'a')]);
+    }
+
+    #[test_case]
+    fn select_graphic_rendition() {
+        let mut attributes = Attributes::DEFAULT;
+        attributes.select_graphic_rendition(&[1, 31, 44]);
+        assert_eq!(
+            attributes,
+            Attributes {
+                foreground: 12,
+                background: 1,
+                bold: true,
+            }
+        );
+        attributes.select_graphic_rendition(&[22]);
+        assert_eq!(attributes.foreground, 4);
+        attributes.select_graphic_rendition(&[]);
+        assert_eq!(attributes, Attributes::DEFAULT);
+    }
+}
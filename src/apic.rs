@@ -0,0 +1,170 @@
+// Local APIC, it gets the interrupts from the I/O APIC and has a timer that takes over from the PIT
+use crate::clock;
+use crate::interrupts::{APIC_SPURIOUS_VECTOR, APIC_TIMER_VECTOR};
+use core::arch::x86_64::__cpuid;
+use core::fmt;
+use core::hint::spin_loop;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::registers::model_specific::Msr;
+use x86_64::structures::paging::{
+    mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, PhysFrame, Size4KiB,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+// Where the registers get mapped
+pub const APIC_START: usize = 0x_6666_6666_0000;
+
+// Model specific registers
+const IA32_APIC_BASE: u32 = 0x1B;
+const IA32_TSC_DEADLINE: u32 = 0x6E0;
+const GLOBAL_ENABLE: u64 = 1 << 11;
+
+// Registers, as offsets from the base
+const ID: usize = 0x20;
+const EOI: usize = 0xB0;
+const SPURIOUS: usize = 0xF0;
+const LVT_TIMER: usize = 0x320;
+const TIMER_INITIAL_COUNT: usize = 0x380;
+const TIMER_CURRENT_COUNT: usize = 0x390;
+const TIMER_DIVIDE: usize = 0x3E0;
+
+const SOFTWARE_ENABLE: u32 = 1 << 8;
+// Timer bits
+const MASKED: u32 = 1 << 16;
+const PERIODIC: u32 = 1 << 17;
+const TSC_DEADLINE: u32 = 2 << 17;
+const DIVIDE_BY_16: u32 = 0x3;
+
+// The timer interrupts this often, same as the PIT did
+pub const TIMER_FREQUENCY: u64 = 1000;
+// How many PIT ticks the timer is measured for
+const CALIBRATION_TICKS: u64 = 50;
+
+// TSC cycles between interrupts in TSC-deadline mode, 0 in periodic mode
+static DEADLINE_INTERVAL: AtomicU64 = AtomicU64::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerMode {
+    // Counts down from a value that was measured against the PIT
+    Periodic { frequency: u64 },
+    // Fires when the TSC gets to a deadline, which has to be set again every time
+    TscDeadline,
+}
+
+impl fmt::Display for TimerMode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TimerMode::Periodic { frequency } => {
+                write!(f, "periodic, counting at {} kHz", frequency / 1000)
+            }
+            TimerMode::TscDeadline => write!(f, "TSC-deadline"),
+        }
+    }
+}
+
+fn read(register: usize) -> u32 {
+    unsafe { core::ptr::read_volatile((APIC_START + register) as *const u32) }
+}
+
+fn write(register: usize, value: u32) {
+    unsafe { core::ptr::write_volatile((APIC_START + register) as *mut u32, value) }
+}
+
+fn has_apic() -> bool {
+    unsafe { __cpuid(1).edx & (1 << 9) != 0 }
+}
+
+fn has_tsc_deadline() -> bool {
+    unsafe { __cpuid(1).ecx & (1 << 24) != 0 }
+}
+
+// Must be called after the clock is initialized, the timer is measured against it
+// Returns None if there is no local APIC or its timer couldn't be calibrated, the PIT keeps ticking then
+pub fn init(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<Option<TimerMode>, MapToError<Size4KiB>> {
+    use x86_64::instructions::interrupts;
+
+    if !has_apic() {
+        return Ok(None);
+    }
+
+    let mut base_msr = Msr::new(IA32_APIC_BASE);
+    let base = unsafe { base_msr.read() };
+    unsafe { base_msr.write(base | GLOBAL_ENABLE) };
+
+    let page = Page::containing_address(VirtAddr::new(APIC_START as u64));
+    let frame = PhysFrame::containing_address(PhysAddr::new(base & 0x000F_FFFF_FFFF_F000));
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE;
+    unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() };
+
+    write(SPURIOUS, SOFTWARE_ENABLE | APIC_SPURIOUS_VECTOR as u32);
+
+    // Count down from the top for a while and see how far it got
+    write(TIMER_DIVIDE, DIVIDE_BY_16);
+    write(LVT_TIMER, MASKED | APIC_TIMER_VECTOR as u32);
+    let start = clock::wait_for_tick();
+    let before = clock::nanoseconds();
+    write(TIMER_INITIAL_COUNT, u32::MAX);
+    while clock::ticks() - start < CALIBRATION_TICKS {
+        spin_loop();
+    }
+    let counted = u32::MAX - read(TIMER_CURRENT_COUNT);
+    let elapsed = clock::nanoseconds() - before;
+    write(TIMER_INITIAL_COUNT, 0);
+    // A clock that didn't move leaves nothing to measure against
+    let frequency = match elapsed {
+        0 => 0,
+        _ => (counted as u128 * 1_000_000_000 / elapsed as u128) as u64,
+    };
+
+    // The deadlines are in TSC ticks, they only keep time if the TSC always runs at the same rate
+    let mode = if has_tsc_deadline() && clock::has_invariant_tsc() && clock::tsc_frequency() != 0 {
+        TimerMode::TscDeadline
+    } else if frequency >= TIMER_FREQUENCY {
+        TimerMode::Periodic { frequency }
+    } else {
+        return Ok(None);
+    };
+
+    interrupts::without_interrupts(|| {
+        clock::set_tick_period(1_000_000_000_000 / TIMER_FREQUENCY);
+        clock::stop_pit();
+
+        match mode {
+            TimerMode::Periodic { frequency } => {
+                write(LVT_TIMER, PERIODIC | APIC_TIMER_VECTOR as u32);
+                write(TIMER_INITIAL_COUNT, (frequency / TIMER_FREQUENCY) as u32);
+            }
+            TimerMode::TscDeadline => {
+                DEADLINE_INTERVAL
+                    .store(clock::tsc_frequency() / TIMER_FREQUENCY, Ordering::Relaxed);
+                write(LVT_TIMER, TSC_DEADLINE | APIC_TIMER_VECTOR as u32);
+                set_next_deadline();
+            }
+        }
+    });
+
+    Ok(Some(mode))
+}
+
+fn set_next_deadline() {
+    let deadline = clock::rdtsc() + DEADLINE_INTERVAL.load(Ordering::Relaxed);
+    unsafe { Msr::new(IA32_TSC_DEADLINE).write(deadline) };
+}
+
+pub fn timer_interrupt_handler() {
+    if DEADLINE_INTERVAL.load(Ordering::Relaxed) != 0 {
+        set_next_deadline();
+    }
+    clock::tick();
+}
+
+pub fn id() -> u8 {
+    (read(ID) >> 24) as u8
+}
+
+pub fn end_of_interrupt() {
+    write(EOI, 0);
+}
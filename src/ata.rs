@@ -1,12 +1,21 @@
 // ATA Driver!
-use crate::println;
+use crate::clock::Instant;
+use crate::{debug, trace, warn};
 use alloc::{string::String, vec::Vec};
 use bit_field::BitField;
 use core::hint::spin_loop;
+use core::time::Duration;
 use lazy_static::lazy_static;
 use spin::Mutex;
 use x86_64::instructions::port::{Port, PortReadOnly, PortWriteOnly};
 
+const PRIMARY_IO_BASE: u16 = 0x1F0;
+const PRIMARY_CONTROL_BASE: u16 = 0x3F6;
+pub const PRIMARY_IRQ: u8 = 14;
+
+// How long a drive can stay busy before we reset the bus
+const BUSY_TIMEOUT: Duration = Duration::from_secs(1);
+
 // Commands to send to the drives
 #[repr(u16)]
 enum Command {
@@ -122,9 +131,9 @@ impl Bus {
 
     fn busy_loop(&mut self) {
         self.wait();
-        let start = crate::clock::uptime();
+        let start = Instant::now();
         while self.is_busy() {
-            if crate::clock::uptime() - start > 1.0 {
+            if start.elapsed() > BUSY_TIMEOUT {
                 return self.reset();
             }
 
@@ -158,29 +167,32 @@ impl Bus {
         self.write_command(Command::Identify);
 
         if self.status() == 0 {
-            println!("status 0");
+            debug!("bus {} drive {}: status is 0, no drive", self.id, drive);
             return None;
         }
 
         self.busy_loop();
 
         if self.lba1() != 0 || self.lba2() != 0 {
-            println!("lba thingies");
+            debug!("bus {} drive {}: not an ATA drive", self.id, drive);
             return None;
         }
 
         for i in 0.. {
             if i == 256 {
-                println!("i 256");
+                warn!(
+                    "bus {} drive {}: timed out waiting for IDENTIFY",
+                    self.id, drive
+                );
                 self.reset();
                 return None;
             }
             if self.is_error() {
-                println!("Is error");
+                warn!("bus {} drive {}: IDENTIFY failed", self.id, drive);
                 return None;
             }
             if self.is_ready() {
-                println!("ready");
+                trace!("bus {} drive {}: ready after {} polls", self.id, drive, i);
                 break;
             }
         }
@@ -195,7 +207,24 @@ impl Bus {
 }
 
 lazy_static! {
-    pub static ref BUS: Mutex<Bus> = Mutex::new(Bus::new(0, 0x1F0, 0x3F6, 14));
+    pub static ref BUS: Mutex<Bus> = Mutex::new(Bus::new(
+        0,
+        PRIMARY_IO_BASE,
+        PRIMARY_CONTROL_BASE,
+        PRIMARY_IRQ
+    ));
+}
+
+// The driver polls, but a drive keeps its interrupt raised until the status is read
+// Not through BUS, the lock is held while waiting for the drive
+fn interrupt_handler() -> bool {
+    let mut status: PortReadOnly<u8> = PortReadOnly::new(PRIMARY_IO_BASE + 7);
+    unsafe { status.read() };
+    true
+}
+
+pub fn init() {
+    crate::interrupts::register(PRIMARY_IRQ, "ata", interrupt_handler).expect("ATA IRQ is taken");
 }
 
 fn disk_size(sectors: u32) -> (u32, String) {
@@ -234,7 +263,7 @@ pub fn info() -> Vec<(u8, String, String, u32, String)> {
             let (size, unit) = disk_size(sectors);
             res.push((drive, model, serial, size, unit));
         } else {
-            println!("No drive found!");
+            debug!("No drive {} found", drive);
         }
     }
     res
@@ -0,0 +1,176 @@
+// Walks the stack through the saved frame pointers and names the functions it finds
+// The kernel is built with frame pointers, see .cargo/config.toml
+// The symbol table is filled in after the build by tools/symbols.py, the runner does that
+use crate::exceptions;
+use crate::println;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+const MAX_FRAMES: usize = 32;
+
+// tools/symbols.py has to agree with all of this
+const SYMBOLS_SIZE: usize = 512 * 1024;
+const MAGIC: [u8; 4] = *b"KSYM";
+// What's there until the table is filled in, not all zeros so it's stored in the ELF file
+const EMPTY_MAGIC: [u8; 4] = *b"KSY0";
+// Address (8 bytes), size (4 bytes) and where the name starts in the strings (4 bytes)
+const ENTRY_SIZE: usize = 16;
+
+#[repr(C)]
+struct SymbolTable {
+    magic: [u8; 4],
+    count: u32,
+    // The entries sorted by address, then the names, each one ends with a 0
+    data: [u8; SYMBOLS_SIZE],
+}
+
+// Mutable and not mangled, otherwise the compiler knows it's empty and throws the lookups away
+#[no_mangle]
+#[link_section = ".ksyms"]
+static mut KERNEL_SYMBOLS: SymbolTable = SymbolTable {
+    magic: EMPTY_MAGIC,
+    count: 0,
+    data: [0; SYMBOLS_SIZE],
+};
+
+// Only one backtrace gets printed, a fault while printing it shouldn't start another one
+static PRINTED: AtomicBool = AtomicBool::new(false);
+
+fn symbols() -> Option<(usize, &'static [u8])> {
+    let table = unsafe { &*core::ptr::addr_of!(KERNEL_SYMBOLS) };
+    let count = table.count as usize;
+    if table.magic != MAGIC || count * ENTRY_SIZE > SYMBOLS_SIZE {
+        return None;
+    }
+    Some((count, &table.data))
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(&data[offset..offset + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&data[offset..offset + 8]);
+    u64::from_le_bytes(bytes)
+}
+
+// The function the address is in, and how far into it
+pub fn resolve(address: u64) -> Option<(&'static str, u64)> {
+    let (count, data) = symbols()?;
+    let entry = |i: usize| read_u64(data, i * ENTRY_SIZE);
+
+    // The last symbol that starts at or before the address
+    let (mut low, mut high) = (0, count);
+    while low < high {
+        let middle = (low + high) / 2;
+        if entry(middle) <= address {
+            low = middle + 1;
+        } else {
+            high = middle;
+        }
+    }
+    if low == 0 {
+        return None;
+    }
+
+    let offset = (low - 1) * ENTRY_SIZE;
+    let start = read_u64(data, offset);
+    let size = read_u32(data, offset + 8) as u64;
+    if size != 0 && address >= start + size {
+        return None;
+    }
+
+    let strings = &data[count * ENTRY_SIZE..];
+    let name = strings.get(read_u32(data, offset + 12) as usize..)?;
+    let end = name.iter().position(|&b| b == 0)?;
+    let name = core::str::from_utf8(&name[..end]).ok()?;
+    Some((name, address - start))
+}
+
+// Return addresses point after the call, which can be past the end of the function
+fn print_frame(index: usize, address: u64, return_address: bool) {
+    let lookup = if return_address {
+        address.saturating_sub(1)
+    } else {
+        address
+    };
+    match resolve(lookup) {
+        Some((name, offset)) => println!(
+            "  {:>2}: {:#018x} {}+{:#x}",
+            index,
+            address,
+            name,
+            offset + (address - lookup)
+        ),
+        None => println!("  {:>2}: {:#018x} ???", index, address),
+    }
+}
+
+fn walk(mut rbp: u64, mut index: usize) {
+    while index < MAX_FRAMES {
+        // Every frame has the caller's rbp, then the return address
+        // A corrupted rbp can be anything, so nothing here is allowed to panic
+        if rbp == 0 || rbp & 7 != 0 {
+            return;
+        }
+        let slot = match rbp.checked_add(8) {
+            Some(slot) => slot,
+            None => return,
+        };
+        let (next, return_address) = match (exceptions::read_u64(rbp), exceptions::read_u64(slot)) {
+            (Some(next), Some(return_address)) => (next, return_address),
+            _ => return,
+        };
+        if return_address == 0 {
+            return;
+        }
+        print_frame(index, return_address, true);
+        index += 1;
+
+        // The stack grows down, so callers are always further up
+        if next <= rbp {
+            return;
+        }
+        rbp = next;
+    }
+}
+
+fn print_from(rip: u64, rbp: u64, return_address: bool) {
+    if symbols().is_none() {
+        println!("Backtrace (no symbols, run the kernel through tools/symbols.py):");
+    } else {
+        println!("Backtrace:");
+    }
+    print_frame(0, rip, return_address);
+    walk(rbp, 1);
+}
+
+// For exceptions, starting at the instruction that faulted
+pub fn print_fatal(rip: u64, rbp: u64) {
+    if !PRINTED.swap(true, Ordering::Relaxed) {
+        // rip is where the fault happened, not a return address
+        print_from(rip, rbp, false);
+    }
+}
+
+// For panics, unless an exception already printed one
+#[inline(never)]
+pub fn print_on_panic() {
+    if PRINTED.swap(true, Ordering::Relaxed) {
+        return;
+    }
+    let rbp: u64;
+    unsafe { core::arch::asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack)) };
+    // The first frame is whoever called this, the panic handler
+    // A panic can come from a corrupted stack, so this gets the same checks as walk
+    let frame = rbp
+        .checked_add(8)
+        .and_then(exceptions::read_u64)
+        .zip(exceptions::read_u64(rbp));
+    match frame {
+        Some((rip, rbp)) => print_from(rip, rbp, true),
+        None => println!("Backtrace: the stack is unreadable"),
+    }
+}
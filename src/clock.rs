@@ -1,24 +1,157 @@
+// Time since boot in nanoseconds, and the wall clock on top of it
+// It comes from the TSC if that runs at a constant rate, then the HPET if there is one,
+// otherwise from counting timer ticks
+// No floating point in here, the kernel is built without SSE
+use crate::hpet;
+use crate::interrupts::{self, HandlerId};
+use core::arch::x86_64::__cpuid;
+use core::convert::TryFrom;
+use core::fmt;
 use core::hint::spin_loop;
-use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use core::ops::{Add, Sub};
+use core::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use core::time::Duration;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
 use x86_64::instructions::port::Port;
 
-static CLOCKS_PER_NANOSECOND: AtomicU64 = AtomicU64::new(0);
-static PIT_TICKS: AtomicUsize = AtomicUsize::new(0);
-const PIT_FREQUENCY: f64 = 3_579_545.0 / 3.0;
-const PIT_DIVIDER: usize = 1193;
-const PIT_INTERVAL: f64 = (PIT_DIVIDER as f64) / PIT_FREQUENCY;
+pub const PIT_IRQ: u8 = 0;
+const PIT_FREQUENCY: u64 = 1_193_182;
+const PIT_DIVIDER: u64 = 1193;
+// In picoseconds, a little under 1 ms
+const PIT_PERIOD: u64 = PIT_DIVIDER * 1_000_000_000_000 / PIT_FREQUENCY;
 
-fn rdtsc() -> u64 {
+// How many PIT ticks the TSC is measured for, about 250 ms
+const CALIBRATION_TICKS: u64 = 250;
+// The HPET is precise enough that a shorter measurement does
+const HPET_CALIBRATION_TIME: Duration = Duration::from_millis(10);
+
+// Timer interrupts since boot, from the PIT or the local APIC timer
+static TICKS: AtomicU64 = AtomicU64::new(0);
+// In picoseconds, so the PIT's odd period doesn't add up to a noticeable error
+static TICK_PERIOD: AtomicU64 = AtomicU64::new(PIT_PERIOD);
+// When the current tick source took over
+static BASE_TICKS: AtomicU64 = AtomicU64::new(0);
+static BASE_NANOSECONDS: AtomicU64 = AtomicU64::new(0);
+
+// In Hz, 0 until it's calibrated
+static TSC_FREQUENCY: AtomicU64 = AtomicU64::new(0);
+// What the TSC would have read at boot
+static TSC_START: AtomicU64 = AtomicU64::new(0);
+
+// The HPET starts counting after boot, this is where it took over
+static HPET_START: AtomicU64 = AtomicU64::new(0);
+static HPET_BASE_NANOSECONDS: AtomicU64 = AtomicU64::new(0);
+
+static SOURCE: AtomicU8 = AtomicU8::new(Source::Ticks as u8);
+
+// Until the local APIC timer takes over
+static PIT_HANDLER: Mutex<Option<HandlerId>> = Mutex::new(None);
+
+// Unix time when we booted, so the wall clock doesn't have to read the RTC every time
+static BOOT_TIME: AtomicU64 = AtomicU64::new(0);
+
+// An invariant TSC always wins, reading the HPET is an uncached MMIO read and much slower
+// The HPET only takes over from timer ticks
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Source {
+    Ticks,
+    Tsc,
+    Hpet,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Source::Ticks => "timer ticks",
+            Source::Tsc => "TSC",
+            Source::Hpet => "HPET",
+        };
+        f.write_str(name)
+    }
+}
+
+// A point in time since boot, in nanoseconds
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Instant(u64);
+
+impl Instant {
+    pub fn now() -> Instant {
+        Instant(nanoseconds())
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        Instant::now() - *self
+    }
+}
+
+// Durations past what a u64 can hold are as good as forever
+pub fn nanoseconds_in(duration: Duration) -> u64 {
+    u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX)
+}
+
+// Stops at the end of time instead of wrapping around
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, duration: Duration) -> Instant {
+        Instant(self.0.saturating_add(nanoseconds_in(duration)))
+    }
+}
+
+// Zero if the other instant is later
+impl Sub for Instant {
+    type Output = Duration;
+
+    fn sub(self, earlier: Instant) -> Duration {
+        Duration::from_nanos(self.0.saturating_sub(earlier.0))
+    }
+}
+
+// Shows a duration as seconds with 3 decimals, the width applies to the whole thing
+pub struct Seconds(pub Duration);
+
+impl fmt::Display for Seconds {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let width = f.width().unwrap_or(0).saturating_sub(4);
+        write!(
+            f,
+            "{:>width$}.{:03}",
+            self.0.as_secs(),
+            self.0.subsec_millis(),
+            width = width
+        )
+    }
+}
+
+pub fn source() -> Source {
+    match SOURCE.load(Ordering::Relaxed) {
+        0 => Source::Ticks,
+        1 => Source::Tsc,
+        _ => Source::Hpet,
+    }
+}
+
+pub fn rdtsc() -> u64 {
     unsafe {
         core::arch::x86_64::_mm_lfence();
         core::arch::x86_64::_rdtsc()
     }
 }
 
-fn set_pit_freqency_divider(divider: u16, channel: u8) {
-    use x86_64::instructions::interrupts;
+// An invariant TSC keeps the same rate through power states and frequency changes
+pub fn has_invariant_tsc() -> bool {
+    unsafe {
+        if __cpuid(0x8000_0000).eax < 0x8000_0007 {
+            return false;
+        }
+        __cpuid(0x8000_0007).edx & (1 << 8) != 0
+    }
+}
 
-    interrupts::without_interrupts(|| {
+fn set_pit_freqency_divider(divider: u16, channel: u8) {
+    without_interrupts(|| {
         let bytes = divider.to_le_bytes();
         let mut cmd: Port<u8> = Port::new(0x43);
         let mut data: Port<u8> = Port::new(0x40 + channel as u16);
@@ -32,41 +165,164 @@ fn set_pit_freqency_divider(divider: u16, channel: u8) {
     })
 }
 
-fn ticks() -> usize {
-    PIT_TICKS.load(Ordering::Relaxed)
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
 }
 
-pub fn uptime() -> f64 {
-    PIT_INTERVAL * ticks() as f64
+fn tick_nanoseconds(ticks: u64) -> u64 {
+    let elapsed = ticks - BASE_TICKS.load(Ordering::Relaxed);
+    let picoseconds = elapsed as u128 * TICK_PERIOD.load(Ordering::Relaxed) as u128;
+    BASE_NANOSECONDS.load(Ordering::Relaxed) + (picoseconds / 1000) as u64
 }
 
-pub fn sleep(seconds: f64) {
-    let start = uptime();
-    while uptime() - start < seconds {
-        x86_64::instructions::interrupts::enable_and_hlt();
+// Nanoseconds since boot, never goes backwards
+pub fn nanoseconds() -> u64 {
+    match source() {
+        Source::Hpet => {
+            let elapsed = hpet::counter() - HPET_START.load(Ordering::Relaxed);
+            HPET_BASE_NANOSECONDS.load(Ordering::Relaxed) + hpet::to_nanoseconds(elapsed)
+        }
+        Source::Tsc => {
+            let elapsed = rdtsc() - TSC_START.load(Ordering::Relaxed);
+            let frequency = TSC_FREQUENCY.load(Ordering::Relaxed) as u128;
+            (elapsed as u128 * 1_000_000_000 / frequency) as u64
+        }
+        // The base can't change halfway through
+        Source::Ticks => without_interrupts(|| tick_nanoseconds(ticks())),
     }
 }
 
+pub fn uptime() -> Duration {
+    Duration::from_nanos(nanoseconds())
+}
+
+// In Hz, 0 if it couldn't be measured
+pub fn tsc_frequency() -> u64 {
+    TSC_FREQUENCY.load(Ordering::Relaxed)
+}
+
+// Called when a different timer takes over the ticks, the time carries on where it was
+pub fn set_tick_period(picoseconds: u64) {
+    without_interrupts(|| {
+        let ticks = ticks();
+        BASE_NANOSECONDS.store(tick_nanoseconds(ticks), Ordering::Relaxed);
+        BASE_TICKS.store(ticks, Ordering::Relaxed);
+        TICK_PERIOD.store(picoseconds, Ordering::Relaxed);
+    });
+}
+
+// Seconds since 1970-01-01 00:00:00 UTC
+pub fn realtime() -> u64 {
+    boot_time() + nanoseconds() / 1_000_000_000
+}
+
+// Unix time when we booted
+pub fn boot_time() -> u64 {
+    BOOT_TIME.load(Ordering::Relaxed)
+}
+
+pub fn set_realtime(seconds: u64) {
+    BOOT_TIME.store(
+        seconds.saturating_sub(nanoseconds() / 1_000_000_000),
+        Ordering::Relaxed,
+    );
+}
+
+// The HPET is a better reference than the PIT, so the TSC gets measured again against it
+// The clock only switches to the HPET if it isn't on the TSC, the time carries on where it was
+pub fn use_hpet() {
+    let delta = hpet::from_nanoseconds(HPET_CALIBRATION_TIME.as_nanos() as u64);
+    let start = hpet::counter();
+    let a = rdtsc();
+    while hpet::counter() - start < delta {
+        spin_loop();
+    }
+    let b = rdtsc();
+    let elapsed = hpet::to_nanoseconds(hpet::counter() - start);
+    let frequency = ((b - a) as u128 * 1_000_000_000 / elapsed as u128) as u64;
+
+    without_interrupts(|| {
+        let now = nanoseconds();
+        TSC_FREQUENCY.store(frequency, Ordering::Relaxed);
+        if source() == Source::Tsc {
+            let offset = (now as u128 * frequency as u128 / 1_000_000_000) as u64;
+            TSC_START.store(rdtsc().saturating_sub(offset), Ordering::Relaxed);
+        } else {
+            HPET_BASE_NANOSECONDS.store(now, Ordering::Relaxed);
+            HPET_START.store(hpet::counter(), Ordering::Relaxed);
+            SOURCE.store(Source::Hpet as u8, Ordering::Relaxed);
+        }
+    });
+}
+
 pub fn nanowait(nanoseconds: u64) {
+    if hpet::available() {
+        let start = hpet::counter();
+        let delta = hpet::from_nanoseconds(nanoseconds);
+        while hpet::counter() - start < delta {
+            spin_loop();
+        }
+        return;
+    }
+
     let start = rdtsc();
-    let delta = nanoseconds * CLOCKS_PER_NANOSECOND.load(Ordering::Relaxed);
+    let delta = (nanoseconds as u128 * tsc_frequency() as u128 / 1_000_000_000) as u64;
     while rdtsc() - start < delta {
         spin_loop();
     }
 }
 
-pub fn pit_interrupt_handler() {
-    PIT_TICKS.fetch_add(1, Ordering::Relaxed);
+// Waits for the next tick, so measurements start right at the beginning of one
+pub fn wait_for_tick() -> u64 {
+    let start = ticks();
+    while ticks() == start {
+        spin_loop();
+    }
+    ticks()
+}
+
+// Called from whichever timer interrupt is in use
+pub fn tick() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+    crate::timer::tick(nanoseconds());
 }
 
+fn pit_interrupt() -> bool {
+    tick();
+    true
+}
+
+// For when another timer does the ticks
+pub fn stop_pit() {
+    if let Some(id) = without_interrupts(|| PIT_HANDLER.lock().take()) {
+        interrupts::unregister(id);
+    }
+}
+
+// Interrupts have to be enabled, the TSC is measured against the PIT
 pub fn init() {
-    let divider = if PIT_DIVIDER < 65535 { PIT_DIVIDER } else { 0 };
     let channel = 0;
-    set_pit_freqency_divider(divider as u16, channel);
+    set_pit_freqency_divider(PIT_DIVIDER as u16, channel);
+    let id = interrupts::register(PIT_IRQ, "pit", pit_interrupt).expect("PIT IRQ is taken");
+    *PIT_HANDLER.lock() = Some(id);
 
-    let calibration_time = 250_000;
+    let start = wait_for_tick();
     let a = rdtsc();
-    sleep(calibration_time as f64 / 1e6);
+    while ticks() - start < CALIBRATION_TICKS {
+        spin_loop();
+    }
     let b = rdtsc();
-    CLOCKS_PER_NANOSECOND.store((b - a) / calibration_time, Ordering::Relaxed);
+    let picoseconds = (CALIBRATION_TICKS * PIT_PERIOD) as u128;
+    let frequency = ((b - a) as u128 * 1_000_000_000_000 / picoseconds) as u64;
+    TSC_FREQUENCY.store(frequency, Ordering::Relaxed);
+
+    if has_invariant_tsc() {
+        // Carry on from the time the ticks have counted so far
+        without_interrupts(|| {
+            let now = tick_nanoseconds(ticks());
+            let offset = (now as u128 * frequency as u128 / 1_000_000_000) as u64;
+            TSC_START.store(rdtsc().saturating_sub(offset), Ordering::Relaxed);
+            SOURCE.store(Source::Tsc as u8, Ordering::Relaxed);
+        });
+    }
 }
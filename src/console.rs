@@ -0,0 +1,346 @@
+// Text output that works the same on the VGA text buffer and the framebuffer
+use crate::ansi::{Action, Attributes, Csi, Parser};
+use crate::cp437;
+use crate::framebuffer::FramebufferConsole;
+use crate::serial;
+use crate::vga_buffer::{self, Color};
+use core::fmt;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+pub trait Console {
+    // Text can contain ANSI escape sequences
+    fn write_string(&mut self, s: &str);
+    // Move the cursor back over characters that were already printed
+    fn move_back(&mut self, characters: usize);
+    fn change_color(&mut self, foreground: Color, background: Color);
+    // Columns and rows
+    fn size(&self) -> (usize, usize);
+}
+
+impl fmt::Write for dyn Console + '_ {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        self.write_string(s);
+        Ok(())
+    }
+}
+
+// Tab stops are every 8 columns
+const TAB_WIDTH: usize = 8;
+
+// Where the cursor is and what the text looks like, the same for every kind of console
+pub struct TerminalState {
+    columns: usize,
+    rows: usize,
+    row: usize,
+    // Can be one past the end of the row, the line only wraps once we write another character
+    column: usize,
+    attributes: Attributes,
+    parser: Parser,
+    saved_position: (usize, usize),
+}
+
+impl TerminalState {
+    // Output starts at the beginning of row
+    pub fn new(columns: usize, rows: usize, row: usize) -> TerminalState {
+        TerminalState {
+            columns,
+            rows,
+            row,
+            column: 0,
+            attributes: Attributes::DEFAULT,
+            parser: Parser::new(),
+            saved_position: (row, 0),
+        }
+    }
+
+    pub fn size(&self) -> (usize, usize) {
+        (self.columns, self.rows)
+    }
+
+    // Where the next character goes, as (row, column)
+    pub fn cursor(&self) -> (usize, usize) {
+        (self.row, core::cmp::min(self.column, self.columns - 1))
+    }
+
+    pub fn attributes(&self) -> Attributes {
+        self.attributes
+    }
+
+    pub fn set_colors(&mut self, foreground: Color, background: Color) {
+        self.attributes = Attributes {
+            foreground: foreground as u8,
+            background: background as u8,
+            bold: false,
+        };
+    }
+}
+
+// Escape sequences, wrapping and scrolling, on top of a grid of character cells
+// A console only draws the cells and scrolls them, everything else comes with the trait
+pub trait Terminal {
+    fn terminal(&mut self) -> &mut TerminalState;
+    // Draws a character with the current attributes
+    fn put(&mut self, row: usize, column: usize, glyph: u8);
+    // Moves every row up by one and blanks the bottom one with the current attributes
+    fn scroll(&mut self);
+
+    fn write_text(&mut self, s: &str) {
+        for character in s.chars() {
+            // Escape sequences are always ASCII, anything else is shown using its code page 437 glyph
+            if !character.is_ascii() {
+                self.write_byte(cp437::encode(character).unwrap_or(0xfe));
+                continue;
+            }
+
+            let action = self.terminal().parser.advance(character as u8);
+            match action {
+                Some(Action::Print(byte)) => match byte {
+                    // Only write printable ASCII characters
+                    0x20..=0x7e | b'\n' | b'\r' | b'\t' | 0x08 => self.write_byte(byte),
+                    _ => self.write_byte(0xfe),
+                },
+                Some(Action::Escape(byte)) => self.escape(byte),
+                Some(Action::Csi(csi)) => self.csi(&csi),
+                None => {}
+            }
+        }
+    }
+
+    fn write_byte(&mut self, byte: u8) {
+        match byte {
+            b'\n' => self.new_line(),
+            b'\r' => self.terminal().column = 0,
+            b'\t' => loop {
+                self.write_byte(b' ');
+                if self.terminal().column % TAB_WIDTH == 0 {
+                    break;
+                }
+            },
+            // Backspace only moves the cursor, it doesn't erase anything
+            0x08 => self.back(1),
+            byte => {
+                if self.terminal().column >= self.terminal().columns {
+                    self.new_line();
+                }
+
+                let state = self.terminal();
+                let (row, column) = (state.row, state.column);
+                state.column += 1;
+                self.put(row, column, byte);
+            }
+        }
+    }
+
+    fn new_line(&mut self) {
+        let state = self.terminal();
+        state.column = 0;
+        if state.row < state.rows - 1 {
+            state.row += 1;
+            return;
+        }
+        // The saved position moves up with the text, so restoring it still lands on the same character
+        state.saved_position.0 = state.saved_position.0.saturating_sub(1);
+        self.scroll();
+    }
+
+    // Move the cursor back, going up to previous rows if needed
+    fn back(&mut self, characters: usize) {
+        if characters == 0 {
+            return;
+        }
+
+        let state = self.terminal();
+        let pos = state.row * state.columns + state.column;
+        let pos = pos.saturating_sub(characters);
+        state.row = pos / state.columns;
+        state.column = pos % state.columns;
+    }
+
+    fn move_to(&mut self, row: usize, column: usize) {
+        let state = self.terminal();
+        state.row = core::cmp::min(row, state.rows - 1);
+        state.column = core::cmp::min(column, state.columns - 1);
+    }
+
+    fn save_position(&mut self) {
+        let state = self.terminal();
+        state.saved_position = (state.row, state.column);
+    }
+
+    // The column can be one past the end of the row, if that's where the cursor was saved
+    fn restore_position(&mut self) {
+        let state = self.terminal();
+        let (row, column) = state.saved_position;
+        state.row = core::cmp::min(row, state.rows - 1);
+        state.column = core::cmp::min(column, state.columns);
+    }
+
+    // Blank everything from (start_row, start_column) to (end_row, end_column) inclusive
+    fn erase(&mut self, start_row: usize, start_column: usize, end_row: usize, end_column: usize) {
+        let columns = self.terminal().columns;
+        for pos in start_row * columns + start_column..=end_row * columns + end_column {
+            self.put(pos / columns, pos % columns, b' ');
+        }
+    }
+
+    fn escape(&mut self, byte: u8) {
+        let state = self.terminal();
+        let (last_row, last_column) = (state.rows - 1, state.columns - 1);
+        match byte {
+            b'7' => self.save_position(),
+            b'8' => self.restore_position(),
+            b'c' => {
+                state.attributes = Attributes::DEFAULT;
+                self.erase(0, 0, last_row, last_column);
+                self.move_to(0, 0);
+            }
+            _ => {}
+        }
+    }
+
+    fn csi(&mut self, csi: &Csi) {
+        let state = self.terminal();
+        let (row, column) = state.cursor();
+        let (last_row, last_column) = (state.rows - 1, state.columns - 1);
+
+        match csi.action {
+            b'A' => self.move_to(row.saturating_sub(csi.param(0, 1) as usize), column),
+            b'B' => self.move_to(row + csi.param(0, 1) as usize, column),
+            b'C' => self.move_to(row, column + csi.param(0, 1) as usize),
+            b'D' => self.move_to(row, column.saturating_sub(csi.param(0, 1) as usize)),
+            // Positions are 1-based
+            b'H' | b'f' => self.move_to(csi.param(0, 1) as usize - 1, csi.param(1, 1) as usize - 1),
+            b'J' => match csi.params().first().copied().unwrap_or(0) {
+                0 => self.erase(row, column, last_row, last_column),
+                1 => self.erase(0, 0, row, column),
+                _ => self.erase(0, 0, last_row, last_column),
+            },
+            b'K' => match csi.params().first().copied().unwrap_or(0) {
+                0 => self.erase(row, column, row, last_column),
+                1 => self.erase(row, 0, row, column),
+                _ => self.erase(row, 0, row, last_column),
+            },
+            b'm' => state.attributes.select_graphic_rendition(csi.params()),
+            b's' => self.save_position(),
+            b'u' => self.restore_position(),
+            _ => {}
+        }
+    }
+}
+
+lazy_static! {
+    // While this is set, output goes to the framebuffer instead of the VGA consoles
+    static ref FRAMEBUFFER: Mutex<Option<FramebufferConsole>> = Mutex::new(None);
+}
+
+// No console was chosen, output goes to the one on screen
+const ACTIVE_CONSOLE: usize = usize::MAX;
+// Set while a shell command runs, so its output stays on the console it was typed on
+static OUTPUT: AtomicUsize = AtomicUsize::new(ACTIVE_CONSOLE);
+
+pub fn set_output(console: Option<usize>) {
+    OUTPUT.store(console.unwrap_or(ACTIVE_CONSOLE), Ordering::Relaxed);
+}
+
+// Runs f with the console that output goes to
+pub fn with_console<F: FnOnce(&mut dyn Console)>(f: F) {
+    match OUTPUT.load(Ordering::Relaxed) {
+        ACTIVE_CONSOLE => with_console_on(vga_buffer::active(), f),
+        console => with_console_on(console, f),
+    }
+}
+
+// The framebuffer is the only console while it's on, whichever one was asked for
+pub fn with_console_on<F: FnOnce(&mut dyn Console)>(console: usize, f: F) {
+    // Turn off interrupts to avoid a deadlock
+    interrupts::without_interrupts(|| {
+        let mut framebuffer = FRAMEBUFFER.lock();
+        match framebuffer.as_mut() {
+            Some(framebuffer) => f(framebuffer),
+            None => f(&mut *vga_buffer::writer_of(console)),
+        }
+    });
+}
+
+// Returns false if there is no framebuffer to switch to
+pub fn enable_framebuffer() -> bool {
+    if framebuffer_enabled() {
+        return true;
+    }
+
+    match FramebufferConsole::open() {
+        Some(console) => {
+            interrupts::without_interrupts(|| {
+                *FRAMEBUFFER.lock() = Some(console);
+            });
+            true
+        }
+        None => false,
+    }
+}
+
+pub fn disable_framebuffer() {
+    let console = interrupts::without_interrupts(|| FRAMEBUFFER.lock().take());
+    if let Some(console) = console {
+        console.close();
+    }
+}
+
+pub fn framebuffer_enabled() -> bool {
+    interrupts::without_interrupts(|| FRAMEBUFFER.lock().is_some())
+}
+
+#[macro_export]
+macro_rules! print {
+    ($($arg:tt)*) => ($crate::console::_print(format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! println {
+    () => ($crate::print!("\n"));
+    ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
+}
+
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+    print_screen(args);
+    // Everything printed is mirrored to the serial port
+    serial::_print(args);
+}
+
+// Only on screen, for what the serial port shouldn't see
+pub fn print_screen(args: fmt::Arguments) {
+    use core::fmt::Write;
+
+    with_console(|console| {
+        console.write_fmt(args).unwrap();
+    });
+}
+
+// Line editing goes to the shell's own console, not wherever a command's output goes
+pub fn print_on(console: usize, args: fmt::Arguments) {
+    use core::fmt::Write;
+
+    with_console_on(console, |console| {
+        console.write_fmt(args).unwrap();
+    });
+}
+
+pub fn change_color(foreground: Color, background: Color) {
+    with_console(|console| console.change_color(foreground, background));
+    serial::change_color(foreground, background);
+}
+
+// Only used for line editing, so it isn't mirrored either
+pub fn move_back(console: usize, characters: usize) {
+    with_console_on(console, |console| console.move_back(characters));
+}
+
+pub fn size() -> (usize, usize) {
+    let mut size = (0, 0);
+    with_console(|console| size = console.size());
+    size
+}
@@ -0,0 +1,88 @@
+// Code page 437 is the character set built into the VGA hardware
+// Maps unicode characters to the byte that shows the same glyph on screen
+
+// Glyphs for 0x00 - 0x1f, 0x00 is blank
+const LOW: [char; 32] = [
+    '\0', '☺', '☻', '♥', '♦', '♣', '♠', '•', '◘', '○', '◙', '♂', '♀', '♪', '♫', '☼', '►', '◄', '↕',
+    '‼', '¶', '§', '▬', '↨', '↑', '↓', '→', '←', '∟', '↔', '▲', '▼',
+];
+
+// Glyphs for 0x80 - 0xff
+const HIGH: [char; 128] = [
+    'Ç', 'ü', 'é', 'â', 'ä', 'à', 'å', 'ç', 'ê', 'ë', 'è', 'ï', 'î', 'ì', 'Ä', 'Å', 'É', 'æ', 'Æ',
+    'ô', 'ö', 'ò', 'û', 'ù', 'ÿ', 'Ö', 'Ü', '¢', '£', '¥', '₧', 'ƒ', 'á', 'í', 'ó', 'ú', 'ñ', 'Ñ',
+    'ª', 'º', '¿', '⌐', '¬', '½', '¼', '¡', '«', '»', '░', '▒', '▓', '│', '┤', '╡', '╢', '╖', '╕',
+    '╣', '║', '╗', '╝', '╜', '╛', '┐', '└', '┴', '┬', '├', '─', '┼', '╞', '╟', '╚', '╔', '╩', '╦',
+    '╠', '═', '╬', '╧', '╨', '╤', '╥', '╙', '╘', '╒', '╓', '╫', '╪', '┘', '┌', '█', '▄', '▌', '▐',
+    '▀', 'α', 'ß', 'Γ', 'π', 'Σ', 'σ', 'µ', 'τ', 'Φ', 'Θ', 'Ω', 'δ', '∞', 'φ', 'ε', '∩', '≡', '±',
+    '≥', '≤', '⌠', '⌡', '÷', '≈', '°', '∙', '·', '√', 'ⁿ', '²', '■', '\u{a0}',
+];
+
+pub fn encode(character: char) -> Option<u8> {
+    if character.is_ascii() {
+        return Some(character as u8);
+    }
+
+    // Some glyphs do double duty for similar looking characters
+    match character {
+        '⌂' => return Some(0x7f),
+        'β' => return Some(0xe1),
+        'μ' => return Some(0xe6),
+        '∑' => return Some(0xe4),
+        '∅' => return Some(0xed),
+        '∈' => return Some(0xee),
+        _ => {}
+    }
+
+    if let Some(index) = HIGH.iter().position(|&c| c == character) {
+        return Some(0x80 + index as u8);
+    }
+
+    // Index 0 is NUL, it's not a glyph
+    LOW.iter()
+        .skip(1)
+        .position(|&c| c == character)
+        .map(|index| index as u8 + 1)
+}
+
+// The character a byte on screen shows
+pub fn decode(byte: u8) -> char {
+    match byte {
+        0x00 => ' ',
+        0x01..=0x1f => LOW[byte as usize],
+        0x7f => '⌂',
+        0x80..=0xff => HIGH[byte as usize - 0x80],
+        _ => byte as char,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test_case]
+    fn ascii_is_unchanged() {
+        assert_eq!(encode('A'), Some(b'A'));
+        assert_eq!(decode(b'A'), 'A');
+    }
+
+    #[test_case]
+    fn every_glyph_round_trips() {
+        for byte in 1..=0xff {
+            assert_eq!(encode(decode(byte)), Some(byte));
+        }
+    }
+
+    #[test_case]
+    fn look_alikes() {
+        assert_eq!(encode('β'), Some(0xe1));
+        assert_eq!(encode('ß'), Some(0xe1));
+        assert_eq!(encode('μ'), Some(0xe6));
+    }
+
+    #[test_case]
+    fn missing_characters() {
+        assert_eq!(encode('€'), None);
+        assert_eq!(encode('あ'), None);
+    }
+}
@@ -0,0 +1,469 @@
+// CPU exceptions, every one of them goes through the same stub so we get all the registers
+// The stub pushes them next to what the CPU pushed and calls exception_handler with the lot
+use crate::gdt;
+use crate::println;
+use core::arch::global_asm;
+use core::fmt;
+use x86_64::registers::control::{Cr0, Cr2, Cr3, Cr4};
+use x86_64::structures::idt::{Entry, HandlerFunc, InterruptDescriptorTable, PageFaultErrorCode};
+use x86_64::VirtAddr;
+
+// Vectors that matter to the handler
+const DEBUG: u64 = 1;
+const NON_MASKABLE_INTERRUPT: u64 = 2;
+const BREAKPOINT: u64 = 3;
+const OVERFLOW: u64 = 4;
+const DOUBLE_FAULT: u64 = 8;
+const INVALID_TSS: u64 = 10;
+const SEGMENT_NOT_PRESENT: u64 = 11;
+const STACK_SEGMENT_FAULT: u64 = 12;
+const GENERAL_PROTECTION_FAULT: u64 = 13;
+const PAGE_FAULT: u64 = 14;
+
+const NAMES: [&str; 32] = [
+    "DIVIDE ERROR",
+    "DEBUG",
+    "NON MASKABLE INTERRUPT",
+    "BREAKPOINT",
+    "OVERFLOW",
+    "BOUND RANGE EXCEEDED",
+    "INVALID OPCODE",
+    "DEVICE NOT AVAILABLE",
+    "DOUBLE FAULT",
+    "COPROCESSOR SEGMENT OVERRUN",
+    "INVALID TSS",
+    "SEGMENT NOT PRESENT",
+    "STACK SEGMENT FAULT",
+    "GENERAL PROTECTION FAULT",
+    "PAGE FAULT",
+    "RESERVED",
+    "X87 FLOATING POINT",
+    "ALIGNMENT CHECK",
+    "MACHINE CHECK",
+    "SIMD FLOATING POINT",
+    "VIRTUALIZATION",
+    "CONTROL PROTECTION",
+    "RESERVED",
+    "RESERVED",
+    "RESERVED",
+    "RESERVED",
+    "RESERVED",
+    "RESERVED",
+    "HYPERVISOR INJECTION",
+    "VMM COMMUNICATION",
+    "SECURITY",
+    "RESERVED",
+];
+
+// What's on the stack when exception_handler gets called, lowest address first
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct Registers {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub r11: u64,
+    pub r10: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub rbp: u64,
+    pub rdi: u64,
+    pub rsi: u64,
+    pub rdx: u64,
+    pub rcx: u64,
+    pub rbx: u64,
+    pub rax: u64,
+    pub vector: u64,
+    // The stub pushes a 0 for exceptions that don't have one
+    pub error_code: u64,
+    // From here on it's what the CPU pushed
+    pub rip: u64,
+    pub cs: u64,
+    pub rflags: u64,
+    pub rsp: u64,
+    pub ss: u64,
+}
+
+impl fmt::Display for Registers {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "RIP {:016x}  RSP {:016x}  RFLAGS {:016x}",
+            self.rip, self.rsp, self.rflags
+        )?;
+        writeln!(
+            f,
+            "RAX {:016x}  RBX {:016x}  RCX {:016x}",
+            self.rax, self.rbx, self.rcx
+        )?;
+        writeln!(
+            f,
+            "RDX {:016x}  RSI {:016x}  RDI {:016x}",
+            self.rdx, self.rsi, self.rdi
+        )?;
+        writeln!(
+            f,
+            "RBP {:016x}  R8  {:016x}  R9  {:016x}",
+            self.rbp, self.r8, self.r9
+        )?;
+        writeln!(
+            f,
+            "R10 {:016x}  R11 {:016x}  R12 {:016x}",
+            self.r10, self.r11, self.r12
+        )?;
+        writeln!(
+            f,
+            "R13 {:016x}  R14 {:016x}  R15 {:016x}",
+            self.r13, self.r14, self.r15
+        )?;
+        writeln!(f, "CS {:04x}  SS {:04x}", self.cs, self.ss)?;
+        writeln!(
+            f,
+            "CR0 {:016x}  CR2 {:016x}",
+            Cr0::read_raw(),
+            Cr2::read().as_u64()
+        )?;
+        write!(
+            f,
+            "CR3 {:016x}  CR4 {:016x}",
+            Cr3::read().0.start_address().as_u64(),
+            Cr4::read_raw()
+        )
+    }
+}
+
+// Error code of the exceptions that are about a segment selector
+struct SelectorErrorCode(u64);
+
+impl fmt::Display for SelectorErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.0 == 0 {
+            return f.write_str("0 (not caused by a selector)");
+        }
+        let table = match (self.0 >> 1) & 0b11 {
+            0b00 => "GDT",
+            0b10 => "LDT",
+            _ => "IDT",
+        };
+        write!(
+            f,
+            "{:#x} ({} index {}",
+            self.0,
+            table,
+            (self.0 >> 3) & 0x1FFF
+        )?;
+        if self.0 & 1 != 0 {
+            f.write_str(", external event")?;
+        }
+        f.write_str(")")
+    }
+}
+
+struct PageFault(PageFaultErrorCode);
+
+impl fmt::Display for PageFault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let code = self.0;
+        let cause = if code.contains(PageFaultErrorCode::PROTECTION_VIOLATION) {
+            "protection violation"
+        } else {
+            "page not present"
+        };
+        let access = if code.contains(PageFaultErrorCode::INSTRUCTION_FETCH) {
+            "instruction fetch"
+        } else if code.contains(PageFaultErrorCode::CAUSED_BY_WRITE) {
+            "write"
+        } else {
+            "read"
+        };
+        let mode = if code.contains(PageFaultErrorCode::USER_MODE) {
+            "user"
+        } else {
+            "kernel"
+        };
+        write!(f, "{:#x} ({}, {} {})", code.bits(), cause, mode, access)?;
+        if code.contains(PageFaultErrorCode::MALFORMED_TABLE) {
+            f.write_str(", reserved bit set in a page table")?;
+        }
+        if code.contains(PageFaultErrorCode::PROTECTION_KEY) {
+            f.write_str(", protection key")?;
+        }
+        Ok(())
+    }
+}
+
+// One stub per vector, the ones where the CPU doesn't push an error code push a 0 instead
+// so the stack looks the same for all of them
+global_asm!(
+    r#"
+.macro exception_stub vector, error_code
+.global exception_stub_\vector
+exception_stub_\vector:
+.if \error_code == 0
+    push 0
+.endif
+    push \vector
+    jmp exception_common
+.endm
+
+exception_stub 0, 0
+exception_stub 1, 0
+exception_stub 2, 0
+exception_stub 3, 0
+exception_stub 4, 0
+exception_stub 5, 0
+exception_stub 6, 0
+exception_stub 7, 0
+exception_stub 8, 1
+exception_stub 9, 0
+exception_stub 10, 1
+exception_stub 11, 1
+exception_stub 12, 1
+exception_stub 13, 1
+exception_stub 14, 1
+exception_stub 16, 0
+exception_stub 17, 1
+exception_stub 18, 0
+exception_stub 19, 0
+exception_stub 20, 0
+exception_stub 21, 1
+exception_stub 28, 0
+exception_stub 29, 1
+exception_stub 30, 1
+
+exception_common:
+    push rax
+    push rbx
+    push rcx
+    push rdx
+    push rsi
+    push rdi
+    push rbp
+    push r8
+    push r9
+    push r10
+    push r11
+    push r12
+    push r13
+    push r14
+    push r15
+    mov rdi, rsp
+    cld
+    call exception_handler
+    pop r15
+    pop r14
+    pop r13
+    pop r12
+    pop r11
+    pop r10
+    pop r9
+    pop r8
+    pop rbp
+    pop rdi
+    pop rsi
+    pop rdx
+    pop rcx
+    pop rbx
+    pop rax
+    add rsp, 16
+    iretq
+"#
+);
+
+extern "C" {
+    fn exception_stub_0();
+    fn exception_stub_1();
+    fn exception_stub_2();
+    fn exception_stub_3();
+    fn exception_stub_4();
+    fn exception_stub_5();
+    fn exception_stub_6();
+    fn exception_stub_7();
+    fn exception_stub_8();
+    fn exception_stub_9();
+    fn exception_stub_10();
+    fn exception_stub_11();
+    fn exception_stub_12();
+    fn exception_stub_13();
+    fn exception_stub_14();
+    fn exception_stub_16();
+    fn exception_stub_17();
+    fn exception_stub_18();
+    fn exception_stub_19();
+    fn exception_stub_20();
+    fn exception_stub_21();
+    fn exception_stub_28();
+    fn exception_stub_29();
+    fn exception_stub_30();
+}
+
+// Reads 8 bytes from an address that might not be mapped or canonical
+// If the load faults the exception handler carries on at probe_read_fixup, which returns 0
+global_asm!(
+    r#"
+.global probe_read
+.global probe_read_load
+.global probe_read_fixup
+probe_read:
+probe_read_load:
+    mov rax, [rdi]
+    mov [rsi], rax
+    mov eax, 1
+    ret
+probe_read_fixup:
+    xor eax, eax
+    ret
+"#
+);
+
+extern "C" {
+    fn probe_read(address: u64, value: *mut u64) -> u8;
+    fn probe_read_load();
+    fn probe_read_fixup();
+}
+
+// Instructions that are allowed to fault, and where to carry on when they do
+static FIXUPS: [(unsafe extern "C" fn(), unsafe extern "C" fn()); 1] =
+    [(probe_read_load, probe_read_fixup)];
+
+fn fixup(rip: u64) -> Option<u64> {
+    FIXUPS
+        .iter()
+        .find(|(at, _)| *at as usize as u64 == rip)
+        .map(|(_, to)| *to as usize as u64)
+}
+
+// None instead of a fault, for following pointers that can't be trusted
+pub fn read_u64(address: u64) -> Option<u64> {
+    let mut value = 0;
+    if unsafe { probe_read(address, &mut value) } != 0 {
+        Some(value)
+    } else {
+        None
+    }
+}
+
+fn stub(f: unsafe extern "C" fn()) -> VirtAddr {
+    VirtAddr::new(f as usize as u64)
+}
+
+// The IDT type keeps 9 and 21 to 29 private, they're still 16 byte entries in vector order though
+fn hidden_entry(idt: &mut InterruptDescriptorTable, vector: usize) -> &mut Entry<HandlerFunc> {
+    let entries = idt as *mut InterruptDescriptorTable as *mut Entry<HandlerFunc>;
+    unsafe { &mut *entries.add(vector) }
+}
+
+// The reserved vectors get nothing, the CPU doesn't raise them
+pub fn install(idt: &mut InterruptDescriptorTable) {
+    unsafe {
+        idt.divide_error.set_handler_addr(stub(exception_stub_0));
+        idt.debug.set_handler_addr(stub(exception_stub_1));
+        idt.non_maskable_interrupt
+            .set_handler_addr(stub(exception_stub_2));
+        idt.breakpoint.set_handler_addr(stub(exception_stub_3));
+        idt.overflow.set_handler_addr(stub(exception_stub_4));
+        idt.bound_range_exceeded
+            .set_handler_addr(stub(exception_stub_5));
+        idt.invalid_opcode.set_handler_addr(stub(exception_stub_6));
+        idt.device_not_available
+            .set_handler_addr(stub(exception_stub_7));
+        idt.double_fault
+            .set_handler_addr(stub(exception_stub_8))
+            .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+        idt.invalid_tss.set_handler_addr(stub(exception_stub_10));
+        idt.segment_not_present
+            .set_handler_addr(stub(exception_stub_11));
+        idt.stack_segment_fault
+            .set_handler_addr(stub(exception_stub_12));
+        idt.general_protection_fault
+            .set_handler_addr(stub(exception_stub_13));
+        idt.page_fault.set_handler_addr(stub(exception_stub_14));
+        idt.x87_floating_point
+            .set_handler_addr(stub(exception_stub_16));
+        idt.alignment_check
+            .set_handler_addr(stub(exception_stub_17));
+        idt.machine_check.set_handler_addr(stub(exception_stub_18));
+        idt.simd_floating_point
+            .set_handler_addr(stub(exception_stub_19));
+        idt.virtualization.set_handler_addr(stub(exception_stub_20));
+        hidden_entry(idt, 9).set_handler_addr(stub(exception_stub_9));
+        hidden_entry(idt, 21).set_handler_addr(stub(exception_stub_21));
+        hidden_entry(idt, 28).set_handler_addr(stub(exception_stub_28));
+        hidden_entry(idt, 29).set_handler_addr(stub(exception_stub_29));
+        idt.security_exception
+            .set_handler_addr(stub(exception_stub_30));
+    }
+}
+
+fn dump(registers: &Registers) {
+    let name = NAMES[registers.vector as usize % NAMES.len()];
+    println!("EXCEPTION: {} (vector {})", name, registers.vector);
+    match registers.vector {
+        INVALID_TSS | SEGMENT_NOT_PRESENT | STACK_SEGMENT_FAULT | GENERAL_PROTECTION_FAULT => {
+            println!("Error code: {}", SelectorErrorCode(registers.error_code))
+        }
+        PAGE_FAULT => {
+            let code = PageFaultErrorCode::from_bits_truncate(registers.error_code);
+            println!("Error code: {}", PageFault(code));
+            println!("Accessed address: {:#x}", Cr2::read().as_u64());
+        }
+        _ => println!("Error code: {:#x}", registers.error_code),
+    }
+    println!("{}", registers);
+}
+
+// A fault on a guard page means the stack below it ran out
+// Usually the page fault can't be delivered on that stack either, so it turns into a double fault
+fn check_stack_overflow(registers: &Registers) {
+    let addresses = [Cr2::read(), VirtAddr::new_truncate(registers.rsp)];
+    if let Some(stack) = addresses
+        .iter()
+        .find_map(|&address| crate::stack::guarded_by(address))
+    {
+        println!(
+            "KERNEL STACK OVERFLOW: {} stack ({:#x}-{:#x})",
+            stack.name,
+            stack.bottom().as_u64(),
+            stack.top.as_u64()
+        );
+    }
+}
+
+// Traps return after the instruction that raised them, so the kernel can carry on
+// A fault in one of the FIXUPS instructions carries on at its fixup
+// Everything else is a fault in kernel code, going back would just fault again
+#[no_mangle]
+extern "C" fn exception_handler(registers: &mut Registers) {
+    // Non-canonical addresses raise a general protection fault instead of a page fault
+    if registers.vector == PAGE_FAULT || registers.vector == GENERAL_PROTECTION_FAULT {
+        if let Some(rip) = fixup(registers.rip) {
+            registers.rip = rip;
+            return;
+        }
+    }
+
+    match registers.vector {
+        BREAKPOINT | DEBUG | OVERFLOW => dump(registers),
+        // Nothing raises these on purpose, if one shows up it's worth knowing about
+        NON_MASKABLE_INTERRUPT => dump(registers),
+        // There is no user space, a page fault means the kernel itself touched a bad address
+        PAGE_FAULT => {
+            check_stack_overflow(registers);
+            dump(registers);
+            crate::backtrace::print_fatal(registers.rip, registers.rbp);
+            panic!("kernel page fault at {:#x}", Cr2::read().as_u64());
+        }
+        vector => {
+            if vector == DOUBLE_FAULT {
+                check_stack_overflow(registers);
+            }
+            dump(registers);
+            crate::backtrace::print_fatal(registers.rip, registers.rbp);
+            panic!(
+                "unrecoverable exception: {}",
+                NAMES[vector as usize % NAMES.len()]
+            );
+        }
+    }
+}
@@ -0,0 +1,327 @@
+// Text console drawn on a linear framebuffer
+// The framebuffer comes from the Bochs VBE extensions, so only QEMU's and Bochs' VGA cards
+// (PCI 1234:1111) are supported, always at 1024x768 with 32 bits per pixel
+// The bootloader we use doesn't set up a framebuffer or say where one is
+use crate::ansi::Attributes;
+use crate::console::{Console, Terminal, TerminalState};
+use crate::graphics::{Mode, SavedText, GRAPHICS};
+use crate::pci;
+use crate::psf::Font;
+use crate::vga_buffer::Color;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::instructions::port::Port;
+use x86_64::{
+    structures::paging::{
+        mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, PhysFrame, Size4KiB,
+    },
+    PhysAddr, VirtAddr,
+};
+
+const VBE_INDEX: u16 = 0x01CE;
+const VBE_DATA: u16 = 0x01CF;
+const VBE_INDEX_ID: u16 = 0;
+const VBE_INDEX_XRES: u16 = 1;
+const VBE_INDEX_YRES: u16 = 2;
+const VBE_INDEX_BPP: u16 = 3;
+const VBE_INDEX_ENABLE: u16 = 4;
+const VBE_ENABLED: u16 = 0x01;
+const VBE_LFB_ENABLED: u16 = 0x40;
+// Oldest version that supports 32 bits per pixel
+const VBE_ID_MIN: u16 = 0xB0C2;
+
+const VENDOR_ID: u16 = 0x1234;
+const DEVICE_ID: u16 = 0x1111;
+
+pub const WIDTH: usize = 1024;
+pub const HEIGHT: usize = 768;
+const BYTES_PER_PIXEL: usize = 4;
+
+pub const FRAMEBUFFER_START: usize = 0x_5555_5555_0000;
+pub const FRAMEBUFFER_SIZE: usize = WIDTH * HEIGHT * BYTES_PER_PIXEL;
+
+static AVAILABLE: AtomicBool = AtomicBool::new(false);
+
+// The 16 text mode colors as 0xRRGGBB
+const PALETTE: [u32; 16] = [
+    0x000000, 0x0000AA, 0x00AA00, 0x00AAAA, 0xAA0000, 0xAA00AA, 0xAA5500, 0xAAAAAA, 0x555555,
+    0x5555FF, 0x55FF55, 0x55FFFF, 0xFF5555, 0xFF55FF, 0xFFFF55, 0xFFFFFF,
+];
+
+fn vbe_read(index: u16) -> u16 {
+    let mut index_port: Port<u16> = Port::new(VBE_INDEX);
+    let mut data_port: Port<u16> = Port::new(VBE_DATA);
+    unsafe {
+        index_port.write(index);
+        data_port.read()
+    }
+}
+
+fn vbe_write(index: u16, value: u16) {
+    let mut index_port: Port<u16> = Port::new(VBE_INDEX);
+    let mut data_port: Port<u16> = Port::new(VBE_DATA);
+    unsafe {
+        index_port.write(index);
+        data_port.write(value);
+    }
+}
+
+// Finds the framebuffer and maps it, without leaving text mode
+// Returns false if there is no framebuffer
+pub fn init(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<bool, MapToError<Size4KiB>> {
+    let device = match pci::find(VENDOR_ID, DEVICE_ID) {
+        Some(device) => device,
+        None => return Ok(false),
+    };
+    if vbe_read(VBE_INDEX_ID) < VBE_ID_MIN {
+        return Ok(false);
+    }
+
+    let physical_start = device.bar(0) as u64;
+    let page_range = {
+        let start = VirtAddr::new(FRAMEBUFFER_START as u64);
+        let end = start + FRAMEBUFFER_SIZE - 1u64;
+        Page::range_inclusive(
+            Page::containing_address(start),
+            Page::containing_address(end),
+        )
+    };
+
+    for (i, page) in page_range.enumerate() {
+        let frame = PhysFrame::containing_address(PhysAddr::new(physical_start + i as u64 * 4096));
+        let flags =
+            PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::WRITE_THROUGH;
+        unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() }
+    }
+
+    AVAILABLE.store(true, Ordering::Relaxed);
+    Ok(true)
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct FramebufferInfo {
+    // Virtual address of the top left pixel
+    pub address: usize,
+    pub width: usize,
+    pub height: usize,
+    // Bytes from one row of pixels to the next
+    pub pitch: usize,
+    pub bytes_per_pixel: usize,
+}
+
+pub struct Framebuffer {
+    info: FramebufferInfo,
+}
+
+impl Framebuffer {
+    pub fn new(info: FramebufferInfo) -> Framebuffer {
+        Framebuffer { info }
+    }
+
+    // Color is 0xRRGGBB
+    pub fn put_pixel(&mut self, x: usize, y: usize, color: u32) {
+        if x >= self.info.width || y >= self.info.height {
+            return;
+        }
+
+        let offset = y * self.info.pitch + x * self.info.bytes_per_pixel;
+        let pixel = (self.info.address + offset) as *mut u8;
+        unsafe {
+            match self.info.bytes_per_pixel {
+                4 => (pixel as *mut u32).write_volatile(color),
+                3 => {
+                    for i in 0..3 {
+                        pixel.add(i).write_volatile((color >> (8 * i)) as u8);
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    pub fn fill_rect(&mut self, x: usize, y: usize, width: usize, height: usize, color: u32) {
+        for row in y..y + height {
+            for col in x..x + width {
+                self.put_pixel(col, row, color);
+            }
+        }
+    }
+
+    // Moves everything up and fills the space left at the bottom with color
+    pub fn scroll_up(&mut self, pixels: usize, color: u32) {
+        let pixels = core::cmp::min(pixels, self.info.height);
+        let address = self.info.address as *mut u8;
+        unsafe {
+            core::ptr::copy(
+                address.add(pixels * self.info.pitch),
+                address,
+                (self.info.height - pixels) * self.info.pitch,
+            );
+        }
+        let (width, height) = (self.info.width, self.info.height);
+        self.fill_rect(0, height - pixels, width, pixels, color);
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Cell {
+    glyph: u8,
+    foreground: u8,
+    background: u8,
+}
+
+pub struct FramebufferConsole {
+    framebuffer: Framebuffer,
+    font: Font<'static>,
+    columns: usize,
+    // What's on screen, so cells can be redrawn
+    cells: Vec<Cell>,
+    terminal: TerminalState,
+    // Text mode gets restored when the console is closed
+    saved_text: SavedText,
+}
+
+impl FramebufferConsole {
+    // Switches the screen to the framebuffer, None if there isn't one or we're not in text mode
+    pub fn open() -> Option<FramebufferConsole> {
+        if !AVAILABLE.load(Ordering::Relaxed) || GRAPHICS.lock().mode() != Mode::Text {
+            return None;
+        }
+
+        let saved_text = SavedText::save();
+
+        vbe_write(VBE_INDEX_ENABLE, 0);
+        vbe_write(VBE_INDEX_XRES, WIDTH as u16);
+        vbe_write(VBE_INDEX_YRES, HEIGHT as u16);
+        vbe_write(VBE_INDEX_BPP, (BYTES_PER_PIXEL * 8) as u16);
+        vbe_write(VBE_INDEX_ENABLE, VBE_ENABLED | VBE_LFB_ENABLED);
+
+        let framebuffer = Framebuffer::new(FramebufferInfo {
+            address: FRAMEBUFFER_START,
+            width: WIDTH,
+            height: HEIGHT,
+            pitch: WIDTH * BYTES_PER_PIXEL,
+            bytes_per_pixel: BYTES_PER_PIXEL,
+        });
+        let font = Font::default();
+        let columns = WIDTH / font.width;
+        let rows = HEIGHT / font.height;
+        let attributes = Attributes::DEFAULT;
+        let blank = Cell {
+            glyph: b' ',
+            foreground: attributes.foreground,
+            background: attributes.background,
+        };
+
+        let mut console = FramebufferConsole {
+            framebuffer,
+            font,
+            columns,
+            cells: vec![blank; columns * rows],
+            terminal: TerminalState::new(columns, rows, 0),
+            saved_text,
+        };
+        console
+            .framebuffer
+            .fill_rect(0, 0, WIDTH, HEIGHT, PALETTE[attributes.background as usize]);
+        console.draw_cursor(true);
+        Some(console)
+    }
+
+    // Goes back to text mode
+    pub fn close(self) {
+        vbe_write(VBE_INDEX_ENABLE, 0);
+        crate::graphics::reset_text_registers();
+        self.saved_text.restore();
+    }
+
+    fn draw_cell(&mut self, row: usize, col: usize, cursor: bool) {
+        let cell = self.cells[row * self.columns + col];
+        let glyph = self.font.glyph(cell.glyph as usize);
+        let bytes_per_row = self.font.bytes_per_row();
+        let foreground = PALETTE[cell.foreground as usize];
+        let background = PALETTE[cell.background as usize];
+        let (left, top) = (col * self.font.width, row * self.font.height);
+
+        for y in 0..self.font.height {
+            // The cursor is an underline
+            let underline = cursor && y + 2 >= self.font.height;
+            for x in 0..self.font.width {
+                let byte = glyph[y * bytes_per_row + x / 8];
+                let set = byte & (0x80 >> (x % 8)) != 0;
+                let color = if set || underline {
+                    foreground
+                } else {
+                    background
+                };
+                self.framebuffer.put_pixel(left + x, top + y, color);
+            }
+        }
+    }
+
+    fn draw_cursor(&mut self, visible: bool) {
+        let (row, col) = self.terminal.cursor();
+        self.draw_cell(row, col, visible);
+    }
+
+    fn blank(&self) -> Cell {
+        let attributes = self.terminal.attributes();
+        Cell {
+            glyph: b' ',
+            foreground: attributes.foreground,
+            background: attributes.background,
+        }
+    }
+}
+
+impl Terminal for FramebufferConsole {
+    fn terminal(&mut self) -> &mut TerminalState {
+        &mut self.terminal
+    }
+
+    fn put(&mut self, row: usize, col: usize, glyph: u8) {
+        self.cells[row * self.columns + col] = Cell {
+            glyph,
+            ..self.blank()
+        };
+        self.draw_cell(row, col, false);
+    }
+
+    fn scroll(&mut self) {
+        let columns = self.columns;
+        let blank = self.blank();
+        self.cells.copy_within(columns.., 0);
+        let len = self.cells.len();
+        for cell in &mut self.cells[len - columns..] {
+            *cell = blank;
+        }
+        let background = PALETTE[blank.background as usize];
+        self.framebuffer.scroll_up(self.font.height, background);
+    }
+}
+
+impl Console for FramebufferConsole {
+    fn write_string(&mut self, s: &str) {
+        self.draw_cursor(false);
+        self.write_text(s);
+        self.draw_cursor(true);
+    }
+
+    fn move_back(&mut self, characters: usize) {
+        self.draw_cursor(false);
+        self.back(characters);
+        self.draw_cursor(true);
+    }
+
+    fn change_color(&mut self, foreground: Color, background: Color) {
+        self.terminal.set_colors(foreground, background);
+    }
+
+    fn size(&self) -> (usize, usize) {
+        self.terminal.size()
+    }
+}
@@ -0,0 +1,303 @@
+// Physical memory manager, a bitmap with one bit per frame, set if the frame is free
+// A second one has the frames that can ever be handed out, so freeing anything else is caught
+// Both live in the first usable region that's big enough for them
+use crate::memory::phys_to_virt;
+use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::structures::paging::{FrameAllocator, FrameDeallocator, PhysFrame, Size4KiB};
+use x86_64::PhysAddr;
+
+const FRAME_SIZE: u64 = 4096;
+const BITS: usize = 64;
+
+struct Bitmap {
+    words: &'static mut [u64],
+    // Set for the usable frames, never changes after init
+    usable_words: &'static mut [u64],
+    // Every frame number below this has a bit
+    frames: usize,
+    usable: usize,
+    free: usize,
+    // Where the search for a free frame starts, everything before it is likely to be used
+    next_word: usize,
+}
+
+static BITMAP: Mutex<Option<Bitmap>> = Mutex::new(None);
+
+#[derive(Debug, Clone, Copy)]
+pub struct FrameStats {
+    // Frames the bootloader said were usable, minus the bitmap
+    pub usable: usize,
+    pub free: usize,
+}
+
+impl FrameStats {
+    pub fn used(&self) -> usize {
+        self.usable - self.free
+    }
+}
+
+impl Bitmap {
+    fn is_free(&self, frame: usize) -> bool {
+        self.words[frame / BITS] & (1 << (frame % BITS)) != 0
+    }
+
+    fn is_usable(&self, frame: usize) -> bool {
+        self.usable_words[frame / BITS] & (1 << (frame % BITS)) != 0
+    }
+
+    fn set_free(&mut self, frame: usize, free: bool) {
+        if free {
+            self.words[frame / BITS] |= 1 << (frame % BITS);
+        } else {
+            self.words[frame / BITS] &= !(1 << (frame % BITS));
+        }
+    }
+
+    // Looks at a whole word at a time, and starts where the last one was found
+    fn allocate(&mut self) -> Option<usize> {
+        let words = self.words.len();
+        for i in 0..words {
+            let index = (self.next_word + i) % words;
+            let word = self.words[index];
+            if word != 0 {
+                let frame = index * BITS + word.trailing_zeros() as usize;
+                self.set_free(frame, false);
+                self.free -= 1;
+                self.next_word = index;
+                return Some(frame);
+            }
+        }
+        None
+    }
+
+    // First fit, align is in frames
+    fn allocate_contiguous(&mut self, count: usize, align: usize) -> Option<usize> {
+        let (mut start, mut run) = (0, 0);
+        for frame in 0..self.frames {
+            if run == 0 && frame % align != 0 {
+                continue;
+            }
+            if !self.is_free(frame) {
+                run = 0;
+                continue;
+            }
+            if run == 0 {
+                start = frame;
+            }
+            run += 1;
+            if run == count {
+                for frame in start..start + count {
+                    self.set_free(frame, false);
+                }
+                self.free -= count;
+                return Some(start);
+            }
+        }
+        None
+    }
+
+    fn deallocate(&mut self, frame: usize) {
+        if frame >= self.frames || !self.is_usable(frame) {
+            panic!(
+                "freeing frame {:#x} which was never usable",
+                frame as u64 * FRAME_SIZE
+            );
+        }
+        if self.is_free(frame) {
+            panic!(
+                "freeing frame {:#x} which isn't allocated",
+                frame as u64 * FRAME_SIZE
+            );
+        }
+        self.set_free(frame, true);
+        self.free += 1;
+        self.next_word = core::cmp::min(self.next_word, frame / BITS);
+    }
+}
+
+// Must be called after memory::init, the bitmap is reached through the physical memory mapping
+pub fn init(memory_map: &'static MemoryMap) {
+    let usable = || {
+        memory_map
+            .iter()
+            .filter(|region| region.region_type == MemoryRegionType::Usable)
+    };
+    let frames = usable()
+        .map(|region| region.range.end_frame_number)
+        .max()
+        .unwrap_or(0) as usize;
+    let words = (frames + BITS - 1) / BITS;
+    // The free bitmap, then the usable one
+    let bitmap_frames = ((words * 2 * 8) as u64 + FRAME_SIZE - 1) / FRAME_SIZE;
+
+    let region = usable()
+        .find(|region| {
+            region.range.end_frame_number - region.range.start_frame_number >= bitmap_frames
+        })
+        .expect("no room for the frame bitmap");
+    let bitmap_start = region.range.start_frame_number;
+    let address = phys_to_virt(PhysAddr::new(bitmap_start * FRAME_SIZE));
+    let all = unsafe { core::slice::from_raw_parts_mut(address.as_mut_ptr::<u64>(), words * 2) };
+
+    // Everything is used, apart from the usable regions
+    for word in all.iter_mut() {
+        *word = 0;
+    }
+    let (words, usable_words) = all.split_at_mut(words);
+    let mut bitmap = Bitmap {
+        words,
+        usable_words,
+        frames,
+        usable: 0,
+        free: 0,
+        next_word: 0,
+    };
+    for region in usable() {
+        for frame in region.range.start_frame_number..region.range.end_frame_number {
+            // The bitmap's own frames, and frame 0 so a null address is never handed out
+            if frame == 0 || (bitmap_start..bitmap_start + bitmap_frames).contains(&frame) {
+                continue;
+            }
+            let frame = frame as usize;
+            bitmap.usable_words[frame / BITS] |= 1 << (frame % BITS);
+            bitmap.set_free(frame, true);
+            bitmap.free += 1;
+        }
+    }
+    bitmap.usable = bitmap.free;
+
+    without_interrupts(|| *BITMAP.lock() = Some(bitmap));
+}
+
+fn with_bitmap<T>(f: impl FnOnce(&mut Bitmap) -> T) -> T {
+    without_interrupts(|| {
+        f(BITMAP
+            .lock()
+            .as_mut()
+            .expect("frames used before frames::init"))
+    })
+}
+
+fn to_frame(frame: usize) -> PhysFrame {
+    PhysFrame::containing_address(PhysAddr::new(frame as u64 * FRAME_SIZE))
+}
+
+pub fn allocate() -> Option<PhysFrame> {
+    with_bitmap(|bitmap| bitmap.allocate()).map(to_frame)
+}
+
+// Unsafe because nothing can still be using the frame
+pub unsafe fn deallocate(frame: PhysFrame) {
+    let frame = (frame.start_address().as_u64() / FRAME_SIZE) as usize;
+    with_bitmap(|bitmap| bitmap.deallocate(frame));
+}
+
+// Physically contiguous frames, for devices doing DMA
+// Returns the first one, which is aligned to align frames
+pub fn allocate_contiguous(count: usize, align: usize) -> Option<PhysFrame> {
+    if count == 0 || align == 0 {
+        return None;
+    }
+    with_bitmap(|bitmap| bitmap.allocate_contiguous(count, align)).map(to_frame)
+}
+
+pub unsafe fn deallocate_contiguous(first: PhysFrame, count: usize) {
+    for i in 0..count as u64 {
+        deallocate(first + i);
+    }
+}
+
+pub fn stats() -> FrameStats {
+    with_bitmap(|bitmap| FrameStats {
+        usable: bitmap.usable,
+        free: bitmap.free,
+    })
+}
+
+// For the page mapping functions, they all take a frame allocator
+pub struct Allocator;
+
+unsafe impl FrameAllocator<Size4KiB> for Allocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame> {
+        allocate()
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for Allocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame) {
+        deallocate(frame);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::boxed::Box;
+    use alloc::vec;
+
+    // 128 frames, the ones in usable are free, the rest were never usable
+    fn bitmap(usable: core::ops::Range<usize>) -> Bitmap {
+        let frames = 128;
+        let words = Box::leak(vec![0u64; frames / BITS].into_boxed_slice());
+        let usable_words = Box::leak(vec![0u64; frames / BITS].into_boxed_slice());
+        let mut bitmap = Bitmap {
+            words,
+            usable_words,
+            frames,
+            usable: usable.len(),
+            free: usable.len(),
+            next_word: 0,
+        };
+        for frame in usable {
+            bitmap.set_free(frame, true);
+            bitmap.usable_words[frame / BITS] |= 1 << (frame % BITS);
+        }
+        bitmap
+    }
+
+    #[test_case]
+    fn allocate_skips_unusable_frames() {
+        let mut bitmap = bitmap(3..128);
+        assert_eq!(bitmap.allocate(), Some(3));
+        assert_eq!(bitmap.allocate(), Some(4));
+        assert_eq!(bitmap.free, 123);
+        assert!(!bitmap.is_free(3));
+    }
+
+    #[test_case]
+    fn freed_frames_are_reused() {
+        let mut bitmap = bitmap(0..128);
+        for frame in 0..70 {
+            assert_eq!(bitmap.allocate(), Some(frame));
+        }
+        bitmap.deallocate(5);
+        assert_eq!(bitmap.free, 59);
+        assert_eq!(bitmap.allocate(), Some(5));
+    }
+
+    #[test_case]
+    fn runs_out() {
+        let mut bitmap = bitmap(126..128);
+        assert_eq!(bitmap.allocate(), Some(126));
+        assert_eq!(bitmap.allocate(), Some(127));
+        assert_eq!(bitmap.allocate(), None);
+        assert_eq!(bitmap.free, 0);
+    }
+
+    #[test_case]
+    fn contiguous_runs() {
+        let mut bitmap = bitmap(0..128);
+        bitmap.allocate();
+        bitmap.allocate();
+        // Frames 2 and 3 are free, but a run of 4 has to start on a multiple of 4
+        assert_eq!(bitmap.allocate_contiguous(4, 4), Some(4));
+        assert_eq!(bitmap.allocate_contiguous(2, 1), Some(2));
+        assert_eq!(bitmap.free, 120);
+        // Straddles the two words
+        bitmap.allocate_contiguous(54, 1);
+        assert_eq!(bitmap.allocate_contiguous(8, 1), Some(62));
+        assert_eq!(bitmap.allocate_contiguous(200, 1), None);
+    }
+}
@@ -11,26 +11,20 @@ struct Selectors {
     tss_selector: SegmentSelector,
 }
 
-lazy_static! {
-    static ref TSS: TaskStateSegment = {
-        let mut tss = TaskStateSegment::new();
-        tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
-            const STACK_SIZE: usize = 4096 * 5;
-            static mut STACK: [u8; STACK_SIZE] = [0; STACK_SIZE];
-
-            let stack_start = VirtAddr::from_ptr(unsafe { &STACK });
-            let stack_end = stack_start + STACK_SIZE;
-            stack_end
-        };
-        tss
-    };
-}
+// Mutable, the double fault stack moves to a guarded one once pages can be mapped
+static mut TSS: TaskStateSegment = TaskStateSegment::new();
+
+// Only until set_interrupt_stack replaces it, it has no guard page
+const EARLY_STACK_SIZE: usize = 4096 * 5;
+static mut EARLY_DOUBLE_FAULT_STACK: [u8; EARLY_STACK_SIZE] = [0; EARLY_STACK_SIZE];
 
 lazy_static! {
     static ref GDT: (GlobalDescriptorTable, Selectors) = {
         let mut gdt = GlobalDescriptorTable::new();
         let code_selector = gdt.add_entry(Descriptor::kernel_code_segment());
-        let tss_selector = gdt.add_entry(Descriptor::tss_segment(&TSS));
+        let tss_selector = gdt.add_entry(Descriptor::tss_segment(unsafe {
+            &*core::ptr::addr_of!(TSS)
+        }));
         (
             gdt,
             Selectors {
@@ -45,9 +39,20 @@ pub fn init_gdt() {
     use x86_64::instructions::segmentation::CS;
     use x86_64::instructions::tables::load_tss;
 
+    let early_stack = VirtAddr::from_ptr(unsafe { core::ptr::addr_of!(EARLY_DOUBLE_FAULT_STACK) });
+    unsafe { set_interrupt_stack(DOUBLE_FAULT_IST_INDEX, early_stack + EARLY_STACK_SIZE) };
+
     GDT.0.load();
     unsafe {
         CS::set_reg(GDT.1.code_selector);
         load_tss(GDT.1.tss_selector);
     }
 }
+
+// The CPU reads the TSS every time it switches stacks, so this works after it's loaded
+// Unsafe because nothing can be using the old stack
+pub unsafe fn set_interrupt_stack(index: u16, top: VirtAddr) {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        (*core::ptr::addr_of_mut!(TSS)).interrupt_stack_table[index as usize] = top;
+    });
+}
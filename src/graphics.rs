@@ -0,0 +1,465 @@
+// VGA graphics mode driver
+// Register values are the standard ones for each mode, see https://wiki.osdev.org/VGA_Hardware
+use crate::memory::phys_to_virt;
+use crate::vga_buffer::{ScreenChar, BUFFER_HEIGHT, BUFFER_WIDTH};
+use alloc::vec::Vec;
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+use x86_64::PhysAddr;
+
+const MISC_WRITE: u16 = 0x3C2;
+const SEQ_INDEX: u16 = 0x3C4;
+const SEQ_DATA: u16 = 0x3C5;
+const DAC_READ_INDEX: u16 = 0x3C7;
+const DAC_WRITE_INDEX: u16 = 0x3C8;
+const DAC_DATA: u16 = 0x3C9;
+const GC_INDEX: u16 = 0x3CE;
+const GC_DATA: u16 = 0x3CF;
+const CRTC_INDEX: u16 = 0x3D4;
+const CRTC_DATA: u16 = 0x3D5;
+const AC_INDEX: u16 = 0x3C0;
+const INSTAT_READ: u16 = 0x3DA;
+
+const SEQ_REGS: usize = 5;
+const CRTC_REGS: usize = 25;
+const GC_REGS: usize = 9;
+const AC_REGS: usize = 21;
+
+// Misc, sequencer, CRT controller, graphics controller, attribute controller
+const TEXT_80X25: [u8; 61] = [
+    0x67, 0x03, 0x00, 0x03, 0x00, 0x02, 0x5F, 0x4F, 0x50, 0x82, 0x55, 0x81, 0xBF, 0x1F, 0x00, 0x4F,
+    0x0D, 0x0E, 0x00, 0x00, 0x00, 0x50, 0x9C, 0x0E, 0x8F, 0x28, 0x1F, 0x96, 0xB9, 0xA3, 0xFF, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x10, 0x0E, 0x00, 0xFF, 0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x14, 0x07,
+    0x38, 0x39, 0x3A, 0x3B, 0x3C, 0x3D, 0x3E, 0x3F, 0x0C, 0x00, 0x0F, 0x08, 0x00,
+];
+
+const GRAPHICS_320X200X256: [u8; 61] = [
+    0x63, 0x03, 0x01, 0x0F, 0x00, 0x0E, 0x5F, 0x4F, 0x50, 0x82, 0x54, 0x80, 0xBF, 0x1F, 0x00, 0x41,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x9C, 0x0E, 0x8F, 0x28, 0x40, 0x96, 0xB9, 0xA3, 0xFF, 0x00,
+    0x00, 0x00, 0x00, 0x00, 0x40, 0x05, 0x0F, 0xFF, 0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07,
+    0x08, 0x09, 0x0A, 0x0B, 0x0C, 0x0D, 0x0E, 0x0F, 0x41, 0x00, 0x0F, 0x00, 0x00,
+];
+
+const GRAPHICS_640X480X16: [u8; 61] = [
+    0xE3, 0x03, 0x01, 0x08, 0x00, 0x06, 0x5F, 0x4F, 0x50, 0x82, 0x54, 0x80, 0x0B, 0x3E, 0x00, 0x40,
+    0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0xEA, 0x0C, 0xDF, 0x28, 0x00, 0xE7, 0x04, 0xE3, 0xFF, 0x00,
+    0x00, 0x00, 0x00, 0x03, 0x00, 0x05, 0x0F, 0xFF, 0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x14, 0x07,
+    0x38, 0x39, 0x3A, 0x3B, 0x3C, 0x3D, 0x3E, 0x3F, 0x01, 0x00, 0x0F, 0x00, 0x00,
+];
+
+// The text mode font lives in plane 2, 32 bytes per character
+const FONT_SIZE: usize = 256 * 32;
+
+fn outb(port: u16, value: u8) {
+    let mut port: Port<u8> = Port::new(port);
+    unsafe { port.write(value) }
+}
+
+fn inb(port: u16) -> u8 {
+    let mut port: Port<u8> = Port::new(port);
+    unsafe { port.read() }
+}
+
+fn read_seq(index: u8) -> u8 {
+    outb(SEQ_INDEX, index);
+    inb(SEQ_DATA)
+}
+
+fn write_seq(index: u8, value: u8) {
+    outb(SEQ_INDEX, index);
+    outb(SEQ_DATA, value);
+}
+
+fn read_gc(index: u8) -> u8 {
+    outb(GC_INDEX, index);
+    inb(GC_DATA)
+}
+
+fn write_gc(index: u8, value: u8) {
+    outb(GC_INDEX, index);
+    outb(GC_DATA, value);
+}
+
+fn write_regs(regs: &[u8; 61]) {
+    let mut regs = regs.iter().copied();
+    let mut next = || regs.next().unwrap();
+
+    outb(MISC_WRITE, next());
+
+    for i in 0..SEQ_REGS {
+        write_seq(i as u8, next());
+    }
+
+    // Unlock the CRTC registers, and keep them unlocked
+    outb(CRTC_INDEX, 0x03);
+    let value = inb(CRTC_DATA);
+    outb(CRTC_DATA, value | 0x80);
+    outb(CRTC_INDEX, 0x11);
+    let value = inb(CRTC_DATA);
+    outb(CRTC_DATA, value & !0x80);
+    for i in 0..CRTC_REGS {
+        let mut value = next();
+        match i {
+            0x03 => value |= 0x80,
+            0x11 => value &= !0x80,
+            _ => {}
+        }
+        outb(CRTC_INDEX, i as u8);
+        outb(CRTC_DATA, value);
+    }
+
+    for i in 0..GC_REGS {
+        write_gc(i as u8, next());
+    }
+
+    // Reading the input status register resets the attribute controller to expect an index
+    for i in 0..AC_REGS {
+        inb(INSTAT_READ);
+        outb(AC_INDEX, i as u8);
+        outb(AC_INDEX, next());
+    }
+
+    // Turn the screen back on
+    inb(INSTAT_READ);
+    outb(AC_INDEX, 0x20);
+}
+
+// For when something other than set_mode took the card out of text mode
+pub(crate) fn reset_text_registers() {
+    write_regs(&TEXT_80X25);
+}
+
+// Gives f direct access to plane 2 at 0xA0000, which is where the text mode font is kept
+pub(crate) fn with_font_plane<F: FnOnce(*mut u8)>(f: F) {
+    let seq2 = read_seq(2);
+    let seq4 = read_seq(4);
+    let gc4 = read_gc(4);
+    let gc5 = read_gc(5);
+    let gc6 = read_gc(6);
+
+    // Only plane 2, without odd/even addressing, mapped at 0xA0000
+    write_seq(2, 0x04);
+    write_seq(4, seq4 | 0x04);
+    write_gc(4, 0x02);
+    write_gc(5, 0x00);
+    write_gc(6, 0x04);
+
+    f(framebuffer());
+
+    write_seq(2, seq2);
+    write_seq(4, seq4);
+    write_gc(4, gc4);
+    write_gc(5, gc5);
+    write_gc(6, gc6);
+}
+
+fn framebuffer() -> *mut u8 {
+    phys_to_virt(PhysAddr::new(0xA0000)).as_mut_ptr()
+}
+
+fn read_palette() -> Vec<u8> {
+    outb(DAC_READ_INDEX, 0);
+    (0..256 * 3).map(|_| inb(DAC_DATA)).collect()
+}
+
+fn write_palette(start: u8, colors: &[u8]) {
+    outb(DAC_WRITE_INDEX, start);
+    for &value in colors {
+        outb(DAC_DATA, value);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    Text,
+    // Mode 13h, one byte per pixel
+    Graphics320x200,
+    // Mode 12h, 16 colors over 4 planes
+    Graphics640x480,
+}
+
+// Everything that graphics modes overwrite, so text mode can be restored afterwards
+pub(crate) struct SavedText {
+    font: Vec<u8>,
+    palette: Vec<u8>,
+    screen: Vec<ScreenChar>,
+}
+
+impl SavedText {
+    // Must be called while still in text mode
+    pub(crate) fn save() -> SavedText {
+        let mut font = Vec::with_capacity(FONT_SIZE);
+        with_font_plane(|plane| {
+            for i in 0..FONT_SIZE {
+                font.push(unsafe { plane.add(i).read_volatile() });
+            }
+        });
+
+        // Interrupt handlers use the writer too
+        let mut screen = Vec::with_capacity(BUFFER_WIDTH * BUFFER_HEIGHT);
+        interrupts::without_interrupts(|| {
+            let writer = crate::vga_buffer::writer();
+            for row in 0..BUFFER_HEIGHT {
+                for col in 0..BUFFER_WIDTH {
+                    screen.push(writer.buffer.chars[row][col].read());
+                }
+            }
+        });
+
+        SavedText {
+            font,
+            palette: read_palette(),
+            screen,
+        }
+    }
+
+    // Must be called after the text mode registers are back
+    pub(crate) fn restore(self) {
+        with_font_plane(|plane| {
+            for (i, &byte) in self.font.iter().enumerate() {
+                unsafe { plane.add(i).write_volatile(byte) };
+            }
+        });
+        write_palette(0, &self.palette);
+
+        interrupts::without_interrupts(|| {
+            let mut writer = crate::vga_buffer::writer();
+            for row in 0..BUFFER_HEIGHT {
+                for col in 0..BUFFER_WIDTH {
+                    writer.buffer.chars[row][col].write(self.screen[row * BUFFER_WIDTH + col]);
+                }
+            }
+        });
+    }
+}
+
+pub struct Graphics {
+    mode: Mode,
+    saved: Option<SavedText>,
+}
+
+impl Graphics {
+    pub fn mode(&self) -> Mode {
+        self.mode
+    }
+
+    pub fn width(&self) -> usize {
+        match self.mode {
+            Mode::Text => 0,
+            Mode::Graphics320x200 => 320,
+            Mode::Graphics640x480 => 640,
+        }
+    }
+
+    pub fn height(&self) -> usize {
+        match self.mode {
+            Mode::Text => 0,
+            Mode::Graphics320x200 => 200,
+            Mode::Graphics640x480 => 480,
+        }
+    }
+
+    pub fn set_mode(&mut self, mode: Mode) {
+        use x86_64::instructions::interrupts;
+
+        if mode == self.mode {
+            return;
+        }
+
+        interrupts::without_interrupts(|| {
+            if self.mode == Mode::Text {
+                self.saved = Some(SavedText::save());
+            }
+
+            match mode {
+                Mode::Text => {
+                    write_regs(&TEXT_80X25);
+                    if let Some(saved) = self.saved.take() {
+                        saved.restore();
+                    }
+                }
+                Mode::Graphics320x200 => {
+                    write_regs(&GRAPHICS_320X200X256);
+                    load_default_palette();
+                }
+                Mode::Graphics640x480 => {
+                    write_regs(&GRAPHICS_640X480X16);
+                    // Mode 12h uses the same 16 colors as text mode
+                    if let Some(saved) = &self.saved {
+                        write_palette(0, &saved.palette);
+                    }
+                    // Write mode 2, the written byte is the color and the bit mask picks the pixels
+                    write_gc(5, 0x02);
+                }
+            }
+        });
+
+        self.mode = mode;
+        self.clear(0);
+    }
+
+    pub fn put_pixel(&mut self, x: usize, y: usize, color: u8) {
+        if x >= self.width() || y >= self.height() {
+            return;
+        }
+
+        match self.mode {
+            Mode::Text => {}
+            Mode::Graphics320x200 => unsafe {
+                framebuffer().add(y * 320 + x).write_volatile(color);
+            },
+            Mode::Graphics640x480 => self.write_planar(y * 80 + x / 8, 0x80 >> (x % 8), color),
+        }
+    }
+
+    // Writes color to the pixels in mask at offset, only in mode 12h
+    fn write_planar(&mut self, offset: usize, mask: u8, color: u8) {
+        write_gc(8, mask);
+        unsafe {
+            let address = framebuffer().add(offset);
+            // The read loads the latches so the pixels outside the mask are kept
+            address.read_volatile();
+            address.write_volatile(color);
+        }
+    }
+
+    fn horizontal_line(&mut self, x0: usize, x1: usize, y: usize, color: u8) {
+        if y >= self.height() || x0 >= self.width() {
+            return;
+        }
+        let x1 = core::cmp::min(x1, self.width() - 1);
+
+        match self.mode {
+            Mode::Text => {}
+            Mode::Graphics320x200 => {
+                for x in x0..=x1 {
+                    self.put_pixel(x, y, color);
+                }
+            }
+            // Write whole bytes at a time where possible
+            Mode::Graphics640x480 => {
+                let mut x = x0;
+                while x <= x1 {
+                    let bit = x % 8;
+                    let count = core::cmp::min(8 - bit, x1 - x + 1);
+                    let mask = ((0xFFu16 << (8 - count)) as u8) >> bit;
+                    self.write_planar(y * 80 + x / 8, mask, color);
+                    x += count;
+                }
+            }
+        }
+    }
+
+    pub fn draw_line(&mut self, x0: usize, y0: usize, x1: usize, y1: usize, color: u8) {
+        // Bresenham's line algorithm
+        let (mut x, mut y) = (x0 as isize, y0 as isize);
+        let (x1, y1) = (x1 as isize, y1 as isize);
+        let dx = (x1 - x).abs();
+        let dy = -(y1 - y).abs();
+        let sx = if x < x1 { 1 } else { -1 };
+        let sy = if y < y1 { 1 } else { -1 };
+        let mut error = dx + dy;
+
+        loop {
+            self.put_pixel(x as usize, y as usize, color);
+            if x == x1 && y == y1 {
+                break;
+            }
+            let e2 = 2 * error;
+            if e2 >= dy {
+                error += dy;
+                x += sx;
+            }
+            if e2 <= dx {
+                error += dx;
+                y += sy;
+            }
+        }
+    }
+
+    pub fn draw_rect(&mut self, x: usize, y: usize, width: usize, height: usize, color: u8) {
+        if width == 0 || height == 0 {
+            return;
+        }
+        let (x1, y1) = (x + width - 1, y + height - 1);
+        self.horizontal_line(x, x1, y, color);
+        self.horizontal_line(x, x1, y1, color);
+        self.draw_line(x, y, x, y1, color);
+        self.draw_line(x1, y, x1, y1, color);
+    }
+
+    pub fn fill_rect(&mut self, x: usize, y: usize, width: usize, height: usize, color: u8) {
+        if width == 0 {
+            return;
+        }
+        for row in y..y + height {
+            self.horizontal_line(x, x + width - 1, row, color);
+        }
+    }
+
+    // Copies a width * height image with one byte per pixel to (x, y)
+    pub fn blit(&mut self, x: usize, y: usize, width: usize, height: usize, pixels: &[u8]) {
+        for row in 0..height {
+            for col in 0..width {
+                if let Some(&color) = pixels.get(row * width + col) {
+                    self.put_pixel(x + col, y + row, color);
+                }
+            }
+        }
+    }
+
+    pub fn clear(&mut self, color: u8) {
+        let (width, height) = (self.width(), self.height());
+        self.fill_rect(0, 0, width, height, color);
+    }
+
+    // Red, green and blue are 6 bit values (0-63)
+    pub fn set_palette(&mut self, index: u8, red: u8, green: u8, blue: u8) {
+        write_palette(index, &[red & 0x3F, green & 0x3F, blue & 0x3F]);
+    }
+}
+
+// Same 16 colors as text mode, then a 6x6x6 color cube and a grayscale ramp
+fn load_default_palette() {
+    const TEXT_COLORS: [(u8, u8, u8); 16] = [
+        (0, 0, 0),
+        (0, 0, 42),
+        (0, 42, 0),
+        (0, 42, 42),
+        (42, 0, 0),
+        (42, 0, 42),
+        (42, 21, 0),
+        (42, 42, 42),
+        (21, 21, 21),
+        (21, 21, 63),
+        (21, 63, 21),
+        (21, 63, 63),
+        (63, 21, 21),
+        (63, 21, 63),
+        (63, 63, 21),
+        (63, 63, 63),
+    ];
+
+    let mut palette = Vec::with_capacity(256 * 3);
+    for &(red, green, blue) in &TEXT_COLORS {
+        palette.extend_from_slice(&[red, green, blue]);
+    }
+    for i in 0..216 {
+        let level = |n: u8| (n as u16 * 63 / 5) as u8;
+        palette.extend_from_slice(&[level(i / 36), level(i / 6 % 6), level(i % 6)]);
+    }
+    for i in 0..24 {
+        let gray = (i * 63 / 23) as u8;
+        palette.extend_from_slice(&[gray, gray, gray]);
+    }
+    write_palette(0, &palette);
+}
+
+lazy_static! {
+    pub static ref GRAPHICS: Mutex<Graphics> = Mutex::new(Graphics {
+        mode: Mode::Text,
+        saved: None,
+    });
+}
@@ -0,0 +1,110 @@
+// High Precision Event Timer, found through ACPI, only its main counter is used
+use crate::acpi;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use x86_64::structures::paging::{
+    mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, PhysFrame, Size4KiB,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+// Where the registers get mapped
+pub const HPET_START: usize = 0x_7777_7777_0000;
+
+// Where the base address is in the ACPI table, after the header and the hardware id
+const TABLE_ADDRESS: u64 = acpi::HEADER_SIZE + 8;
+
+// Registers
+const CAPABILITIES: usize = 0x000;
+const CONFIGURATION: usize = 0x010;
+const MAIN_COUNTER: usize = 0x0F0;
+
+// Capability bits
+const COUNTER_64_BIT: u64 = 1 << 13;
+// Configuration bits
+const ENABLE: u64 = 1 << 0;
+
+// The spec says the period can't be longer than 100 ns
+const MAX_PERIOD: u64 = 100_000_000;
+
+// In femtoseconds, 0 if there is no HPET
+static PERIOD: AtomicU64 = AtomicU64::new(0);
+static COUNTER_64_BIT_SUPPORTED: AtomicBool = AtomicBool::new(false);
+// A 32 bit counter overflows every few minutes, this is the whole count we have seen so far
+static LAST_COUNT: AtomicU64 = AtomicU64::new(0);
+
+fn read(register: usize) -> u64 {
+    unsafe { core::ptr::read_volatile((HPET_START + register) as *const u64) }
+}
+
+fn write(register: usize, value: u64) {
+    unsafe { core::ptr::write_volatile((HPET_START + register) as *mut u64, value) }
+}
+
+pub fn available() -> bool {
+    PERIOD.load(Ordering::Relaxed) != 0
+}
+
+// In Hz
+pub fn frequency() -> u64 {
+    1_000_000_000_000_000 / PERIOD.load(Ordering::Relaxed)
+}
+
+// Counts up since the HPET was initialized, never wraps around
+pub fn counter() -> u64 {
+    use x86_64::instructions::interrupts;
+
+    if COUNTER_64_BIT_SUPPORTED.load(Ordering::Relaxed) {
+        return read(MAIN_COUNTER);
+    }
+
+    interrupts::without_interrupts(|| {
+        let low = unsafe { core::ptr::read_volatile((HPET_START + MAIN_COUNTER) as *const u32) };
+        let last = LAST_COUNT.load(Ordering::Relaxed);
+        let mut count = (last & !0xFFFF_FFFF) | low as u64;
+        if count < last {
+            count += 1 << 32;
+        }
+        LAST_COUNT.store(count, Ordering::Relaxed);
+        count
+    })
+}
+
+pub fn to_nanoseconds(count: u64) -> u64 {
+    (count as u128 * PERIOD.load(Ordering::Relaxed) as u128 / 1_000_000) as u64
+}
+
+pub fn from_nanoseconds(nanoseconds: u64) -> u64 {
+    (nanoseconds as u128 * 1_000_000 / PERIOD.load(Ordering::Relaxed) as u128) as u64
+}
+
+// Must be called after the ACPI tables are found
+// Returns false if there is no HPET
+pub fn init(
+    mapper: &mut impl Mapper<Size4KiB>,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<bool, MapToError<Size4KiB>> {
+    let table = match acpi::find_table(b"HPET") {
+        Some(table) => table,
+        None => return Ok(false),
+    };
+    let address: u64 = acpi::read(table + TABLE_ADDRESS);
+
+    let page = Page::containing_address(VirtAddr::new(HPET_START as u64));
+    let frame = PhysFrame::containing_address(PhysAddr::new(address));
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE;
+    unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() };
+
+    let capabilities = read(CAPABILITIES);
+    let period = capabilities >> 32;
+    if period == 0 || period > MAX_PERIOD {
+        return Ok(false);
+    }
+
+    // Start counting from 0
+    write(CONFIGURATION, read(CONFIGURATION) & !ENABLE);
+    write(MAIN_COUNTER, 0);
+    COUNTER_64_BIT_SUPPORTED.store(capabilities & COUNTER_64_BIT != 0, Ordering::Relaxed);
+    write(CONFIGURATION, read(CONFIGURATION) | ENABLE);
+
+    PERIOD.store(period, Ordering::Relaxed);
+    Ok(true)
+}
@@ -1,6 +1,4 @@
-use crate::gdt;
-use crate::print;
-use crate::println;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
 use lazy_static::lazy_static;
 use pic8259::ChainedPics;
 use spin::Mutex;
@@ -9,21 +7,12 @@ use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
-        unsafe {
-            idt.double_fault
-                .set_handler_fn(double_fault_handler)
-                .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+        crate::exceptions::install(&mut idt);
+        for (irq, &handler) in IRQ_HANDLERS.iter().enumerate() {
+            idt[PIC_1_OFFSET as usize + irq].set_handler_fn(handler);
         }
-        idt.page_fault.set_handler_fn(page_fault_handler);
-        idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_interrupt_handler);
-        idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler);
-        idt.stack_segment_fault
-            .set_handler_fn(stack_segment_fault_handler);
-        idt.segment_not_present
-            .set_handler_fn(segment_not_present_handler);
-        idt.general_protection_fault
-            .set_handler_fn(general_protection_fault_handler);
+        idt[APIC_TIMER_VECTOR as usize].set_handler_fn(apic_timer_interrupt_handler);
+        idt[APIC_SPURIOUS_VECTOR as usize].set_handler_fn(apic_spurious_interrupt_handler);
         idt
     };
 }
@@ -34,167 +23,309 @@ pub fn init() {
     x86_64::instructions::interrupts::enable();
 }
 
-extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
-    println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
+extern "x86-interrupt" fn apic_timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    crate::apic::timer_interrupt_handler();
+    crate::apic::end_of_interrupt();
 }
 
-extern "x86-interrupt" fn double_fault_handler(
-    stack_frame: InterruptStackFrame,
-    _error_code: u64,
-) -> ! {
-    println!("{:#?}", x86_64::registers::control::Cr0::read());
-    panic!("EXCEPTION : DOUBLE FAULT\n{:#?}", stack_frame);
+// The local APIC sends this when an interrupt goes away before it's delivered, it doesn't get an EOI
+extern "x86-interrupt" fn apic_spurious_interrupt_handler(_stack_frame: InterruptStackFrame) {}
+
+pub const PIC_1_OFFSET: u8 = 32;
+pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
+
+// Vectors for the local APIC, above the PICs
+pub const APIC_TIMER_VECTOR: u8 = 0x40;
+pub const APIC_SPURIOUS_VECTOR: u8 = 0xFF;
+
+pub static PICS: Mutex<ChainedPics> =
+    Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
+
+// Set once the I/O APIC has taken over from the PICs
+static APIC_ROUTING: AtomicBool = AtomicBool::new(false);
+
+const PIC_1_COMMAND: u16 = 0x20;
+const PIC_1_DATA: u16 = 0x21;
+const PIC_2_COMMAND: u16 = 0xA0;
+const PIC_2_DATA: u16 = 0xA1;
+// Makes the next read from the command port return the in-service register
+const READ_ISR: u8 = 0x0B;
+const END_OF_INTERRUPT: u8 = 0x20;
+
+pub const IRQS: usize = 16;
+// How many drivers can share one IRQ line
+const HANDLERS_PER_IRQ: usize = 4;
+// The lowest priority line of each PIC, where they send interrupts that went away
+const SPURIOUS_IRQ_1: u8 = 7;
+const SPURIOUS_IRQ_2: u8 = 15;
+// Where the second PIC is chained to the first
+const CASCADE_IRQ: u8 = 2;
+
+// Returns true if the interrupt came from its device, on a shared line every handler gets called
+pub type Handler = fn() -> bool;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HandlerId {
+    irq: u8,
+    slot: usize,
+    // Slots get reused, this tells an old id apart from whoever has the slot now
+    generation: u64,
 }
 
-extern "x86-interrupt" fn page_fault_handler(
-    stack_frame: InterruptStackFrame,
-    error_code: x86_64::structures::idt::PageFaultErrorCode,
-) {
-    println!("EXCEPTION : PAGE FAULT");
-    println!(
-        "Accessed Address: {:?}",
-        x86_64::registers::control::Cr2::read()
-    );
-    println!("Error Code: {:?}", error_code);
-    println!("{:#?}", stack_frame);
-    x86_64::instructions::interrupts::enable_and_hlt();
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegisterError {
+    InvalidIrq(u8),
+    // Already HANDLERS_PER_IRQ handlers on the line
+    Full(u8),
 }
 
-extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    crate::clock::pit_interrupt_handler();
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Timer.as_u8());
-    }
+#[derive(Clone, Copy)]
+struct Registration {
+    name: &'static str,
+    handler: Handler,
+    generation: u64,
 }
 
-extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    use pc_keyboard::{layouts, DecodedKey, HandleControl, KeyCode, Keyboard, ScancodeSet1};
+// No lazy_static, the timer registers its handler before there is a heap
+static HANDLERS: Mutex<[[Option<Registration>; HANDLERS_PER_IRQ]; IRQS]> =
+    Mutex::new([[None; HANDLERS_PER_IRQ]; IRQS]);
+
+// Every registration gets a new one
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+
+// Only used to fill the arrays below, every element is a new atomic
+#[allow(clippy::declare_interior_mutable_const)]
+const ZERO: AtomicU64 = AtomicU64::new(0);
+static COUNTS: [AtomicU64; IRQS] = [ZERO; IRQS];
+// Nobody said it was theirs
+static UNHANDLED: [AtomicU64; IRQS] = [ZERO; IRQS];
+static SPURIOUS: [AtomicU64; IRQS] = [ZERO; IRQS];
+
+// What the irqs command shows
+pub struct IrqStats {
+    pub irq: u8,
+    pub count: u64,
+    pub unhandled: u64,
+    pub spurious: u64,
+    pub handlers: [Option<&'static str>; HANDLERS_PER_IRQ],
+}
+
+// Every IRQ line gets the same kind of entry in the IDT, they all end up in dispatch
+macro_rules! irq_handlers {
+    ($($irq:expr => $name:ident),*) => {
+        $(
+            extern "x86-interrupt" fn $name(_stack_frame: InterruptStackFrame) {
+                dispatch($irq);
+            }
+        )*
+
+        const IRQ_HANDLERS: [extern "x86-interrupt" fn(InterruptStackFrame); IRQS] = [$($name),*];
+    };
+}
+
+irq_handlers!(
+    0 => irq0_handler, 1 => irq1_handler, 2 => irq2_handler, 3 => irq3_handler,
+    4 => irq4_handler, 5 => irq5_handler, 6 => irq6_handler, 7 => irq7_handler,
+    8 => irq8_handler, 9 => irq9_handler, 10 => irq10_handler, 11 => irq11_handler,
+    12 => irq12_handler, 13 => irq13_handler, 14 => irq14_handler, 15 => irq15_handler
+);
+
+// The PICs raise IRQ 7 or 15 when an interrupt goes away before it's acknowledged
+// A real one shows up in the in-service register, a spurious one doesn't
+fn is_spurious(irq: u8) -> bool {
     use x86_64::instructions::port::Port;
 
-    lazy_static! {
-        static ref KEYBOARD: Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>> = {
-            Mutex::new(Keyboard::new(
-                layouts::Us104Key,
-                ScancodeSet1,
-                HandleControl::Ignore,
-            ))
-        };
+    if APIC_ROUTING.load(Ordering::Relaxed) || (irq != SPURIOUS_IRQ_1 && irq != SPURIOUS_IRQ_2) {
+        return false;
     }
 
-    let mut keyboard = KEYBOARD.lock();
-    let mut port = Port::new(0x60); // PS/2 Keyboard Address
-
-    let scancode: u8 = unsafe { port.read() };
-    if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
-        if let Some(key) = keyboard.process_keyevent(key_event) {
-            match key {
-                DecodedKey::Unicode(character) => {
-                    if character == '\u{8}' {
-                        crate::vga_buffer::backspace();
-                    } else if character == '\u{9}' {
-                        print!("    ");
-                    } else if character == '\n' {
-                        use alloc::string::String;
-                        let writer = crate::vga_buffer::WRITER.lock();
-
-                        // Gather all chars in the current row into one ArrayString
-                        let mut builder = String::new();
-                        for character in &writer.buffer.chars[crate::vga_buffer::BUFFER_HEIGHT - 1]
-                        {
-                            builder.push(character.read().ascii_character as char);
-                        }
-
-                        // We can be sure that we have the writer lock so we can safely call this method
-                        unsafe {
-                            crate::vga_buffer::WRITER.force_unlock();
-                        }
-                        crate::shell::evaluate(&builder);
-                    } else {
-                        print!("{}", character)
-                    }
-
-                    let writer = crate::vga_buffer::WRITER.lock();
-                    let col = writer.column_position;
-                    let row = crate::vga_buffer::BUFFER_HEIGHT - 1;
-
-                    crate::vga_buffer::move_cursor(col as u16, row as u16);
-                }
-                DecodedKey::RawKey(key) => {
-                    match key {
-                        KeyCode::ArrowLeft => {
-                            let mut writer = crate::vga_buffer::WRITER.lock();
-                            let col = writer.column_position;
-                            let row = crate::vga_buffer::BUFFER_HEIGHT - 1;
-
-                            // Barrier for the prompt
-                            if col != 4 {
-                                crate::vga_buffer::move_cursor((col as u16) - 1, row as u16);
-                                writer.column_position -= 1;
-                            }
-                        }
-                        KeyCode::ArrowRight => {
-                            let mut writer = crate::vga_buffer::WRITER.lock();
-                            let col = writer.column_position;
-                            let row = crate::vga_buffer::BUFFER_HEIGHT - 1;
-
-                            // We don't need a barrier here because if the cursor reaches the end of the line then the VGA buffer stops it automatically
-                            crate::vga_buffer::move_cursor((col as u16) + 1, row as u16);
-                            writer.column_position += 1;
-                        }
-                        _ => {} // Ignore all other special keys
-                    }
-                }
-            }
+    let port = if irq == SPURIOUS_IRQ_1 {
+        PIC_1_COMMAND
+    } else {
+        PIC_2_COMMAND
+    };
+    let mut command: Port<u8> = Port::new(port);
+    let in_service = unsafe {
+        command.write(READ_ISR);
+        command.read()
+    };
+    in_service & 0x80 == 0
+}
+
+fn dispatch(irq: u8) {
+    if is_spurious(irq) {
+        SPURIOUS[irq as usize].fetch_add(1, Ordering::Relaxed);
+        // The first PIC did see the cascade interrupt from the second one, so it still needs an EOI
+        if irq == SPURIOUS_IRQ_2 {
+            let mut command: x86_64::instructions::port::Port<u8> =
+                x86_64::instructions::port::Port::new(PIC_1_COMMAND);
+            unsafe { command.write(END_OF_INTERRUPT) };
         }
+        return;
     }
+    COUNTS[irq as usize].fetch_add(1, Ordering::Relaxed);
 
-    unsafe {
-        PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
+    // A copy, so handlers can register and unregister handlers themselves
+    let handlers = HANDLERS.lock()[irq as usize];
+    let mut handled = false;
+    for registration in handlers.iter().flatten() {
+        handled |= (registration.handler)();
     }
+    if !handled {
+        UNHANDLED[irq as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    end_of_interrupt(irq);
 }
 
-extern "x86-interrupt" fn general_protection_fault_handler(
-    stack_frame: InterruptStackFrame,
-    _error_code: u64,
-) {
-    panic!("EXCEPTION: GENERAL PROTECTION FAULT\n{:#?}", stack_frame);
+fn end_of_interrupt(irq: u8) {
+    if APIC_ROUTING.load(Ordering::Relaxed) {
+        crate::apic::end_of_interrupt();
+    } else {
+        unsafe { PICS.lock().notify_end_of_interrupt(PIC_1_OFFSET + irq) };
+    }
 }
 
-extern "x86-interrupt" fn stack_segment_fault_handler(
-    stack_frame: InterruptStackFrame,
-    _error_code: u64,
-) {
-    panic!("EXCEPTION: STACK SEGMENT FAULT\n{:#?}", stack_frame);
+// The line gets unmasked with its first handler
+pub fn register(irq: u8, name: &'static str, handler: Handler) -> Result<HandlerId, RegisterError> {
+    if irq as usize >= IRQS || irq == CASCADE_IRQ {
+        return Err(RegisterError::InvalidIrq(irq));
+    }
+
+    let (id, first) = x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut handlers = HANDLERS.lock();
+        let line = &mut handlers[irq as usize];
+        let first = line.iter().all(Option::is_none);
+        let slot = line
+            .iter()
+            .position(Option::is_none)
+            .ok_or(RegisterError::Full(irq))?;
+        let generation = GENERATION.fetch_add(1, Ordering::Relaxed);
+        line[slot] = Some(Registration {
+            name,
+            handler,
+            generation,
+        });
+        Ok((
+            HandlerId {
+                irq,
+                slot,
+                generation,
+            },
+            first,
+        ))
+    })?;
+
+    if first {
+        unmask_irq(irq);
+    }
+    Ok(id)
 }
 
-extern "x86-interrupt" fn segment_not_present_handler(
-    stack_frame: InterruptStackFrame,
-    _error_code: u64,
-) {
-    panic!("EXCEPTION: SEGMENT NOT PRESENT\n{:#?}", stack_frame);
+// The line gets masked again when its last handler is gone
+// Returns false if the handler wasn't registered, or was already unregistered
+pub fn unregister(id: HandlerId) -> bool {
+    let (removed, last) = x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut handlers = HANDLERS.lock();
+        let line = &mut handlers[id.irq as usize];
+        let removed = match line[id.slot] {
+            Some(registration) if registration.generation == id.generation => {
+                line[id.slot] = None;
+                true
+            }
+            _ => false,
+        };
+        (removed, line.iter().all(Option::is_none))
+    });
+
+    if removed && last {
+        set_irq_masked(id.irq, true);
+    }
+    removed
 }
 
-pub const PIC_1_OFFSET: u8 = 32;
-pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
+pub fn irq_stats() -> [IrqStats; IRQS] {
+    let handlers = x86_64::instructions::interrupts::without_interrupts(|| *HANDLERS.lock());
+    let mut stats = [0; IRQS].map(|_| IrqStats {
+        irq: 0,
+        count: 0,
+        unhandled: 0,
+        spurious: 0,
+        handlers: [None; HANDLERS_PER_IRQ],
+    });
+    for (irq, stat) in stats.iter_mut().enumerate() {
+        stat.irq = irq as u8;
+        stat.count = COUNTS[irq].load(Ordering::Relaxed);
+        stat.unhandled = UNHANDLED[irq].load(Ordering::Relaxed);
+        stat.spurious = SPURIOUS[irq].load(Ordering::Relaxed);
+        for (name, registration) in stat.handlers.iter_mut().zip(handlers[irq].iter()) {
+            *name = registration.map(|registration| registration.name);
+        }
+    }
+    stats
+}
 
-pub static PICS: Mutex<ChainedPics> =
-    Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
+// A bit for each IRQ that is unmasked on the PICs
+pub fn pic_enabled_irqs() -> u16 {
+    use x86_64::instructions::port::Port;
+
+    let mut pic_1: Port<u8> = Port::new(PIC_1_DATA);
+    let mut pic_2: Port<u8> = Port::new(PIC_2_DATA);
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let _pics = PICS.lock();
+        let masks = unsafe { u16::from_le_bytes([pic_1.read(), pic_2.read()]) };
+        !masks
+    })
+}
+
+// Masks everything on the PICs, IRQs go through the I/O APIC after this
+pub fn use_apic_routing() {
+    use x86_64::instructions::port::Port;
 
-#[derive(Debug, Clone, Copy)]
-#[repr(u8)]
-pub enum InterruptIndex {
-    Timer = PIC_1_OFFSET,
-    Keyboard,
+    let mut pic_1: Port<u8> = Port::new(PIC_1_DATA);
+    let mut pic_2: Port<u8> = Port::new(PIC_2_DATA);
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let _pics = PICS.lock();
+        unsafe {
+            pic_1.write(0xFF);
+            pic_2.write(0xFF);
+        }
+        APIC_ROUTING.store(true, Ordering::Relaxed);
+    });
 }
 
-impl InterruptIndex {
-    fn as_u8(self) -> u8 {
-        self as u8
+fn set_irq_masked(irq: u8, masked: bool) {
+    use x86_64::instructions::port::Port;
+
+    if APIC_ROUTING.load(Ordering::Relaxed) {
+        return crate::ioapic::set_masked(irq, masked);
     }
 
-    fn as_usize(self) -> usize {
-        usize::from(self.as_u8())
+    let (port, line) = if irq < 8 {
+        (PIC_1_DATA, irq)
+    } else {
+        (PIC_2_DATA, irq - 8)
+    };
+    let mut data: Port<u8> = Port::new(port);
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let _pics = PICS.lock();
+        unsafe {
+            let mask = data.read();
+            if masked {
+                data.write(mask | 1 << line);
+            } else {
+                data.write(mask & !(1 << line));
+            }
+        }
+    });
+}
+
+// The PICs keep whatever masks the BIOS left, so anything past the timer and keyboard has to be unmasked
+fn unmask_irq(irq: u8) {
+    set_irq_masked(irq, false);
+
+    // IRQs on the second PIC come in through the cascade line
+    if irq >= 8 && !APIC_ROUTING.load(Ordering::Relaxed) {
+        unmask_irq(CASCADE_IRQ);
     }
 }
@@ -0,0 +1,231 @@
+// I/O APICs, they take over the IRQ lines from the 8259 PICs
+// The ACPI MADT says where they are and which ISA IRQs are wired differently
+use crate::acpi;
+use crate::interrupts::PIC_1_OFFSET;
+use crate::memory;
+use alloc::vec::Vec;
+use spin::Mutex;
+use x86_64::structures::paging::{
+    mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, PhysFrame, Size4KiB,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+// Where the registers get mapped, one page for each I/O APIC
+pub const IOAPIC_START: usize = 0x_8888_8888_0000;
+
+// Where the entries start in the MADT, after the local APIC address and the flags
+const MADT_ENTRIES: u64 = acpi::HEADER_SIZE + 8;
+// MADT entry types
+const ENTRY_IOAPIC: u8 = 1;
+const ENTRY_OVERRIDE: u8 = 2;
+
+// The register window
+const REGISTER_SELECT: usize = 0x00;
+const REGISTER_DATA: usize = 0x10;
+// Registers
+const VERSION: u32 = 0x01;
+const REDIRECTION_TABLE: u32 = 0x10;
+
+// Redirection entry bits
+const ACTIVE_LOW: u32 = 1 << 13;
+const LEVEL_TRIGGERED: u32 = 1 << 15;
+const MASKED: u32 = 1 << 16;
+
+// Override flags from the MADT
+const POLARITY_MASK: u16 = 0b11;
+const POLARITY_ACTIVE_LOW: u16 = 0b11;
+const TRIGGER_MASK: u16 = 0b1100;
+const TRIGGER_LEVEL: u16 = 0b1100;
+
+pub const ISA_IRQS: usize = 16;
+// The line the second PIC was chained to, nothing is on it
+const CASCADE_IRQ: u8 = 2;
+
+struct IoApic {
+    // Virtual address of the registers
+    address: usize,
+    // The first global system interrupt it handles
+    gsi_base: u32,
+    entries: u32,
+}
+
+impl IoApic {
+    fn read(&self, register: u32) -> u32 {
+        unsafe {
+            core::ptr::write_volatile((self.address + REGISTER_SELECT) as *mut u32, register);
+            core::ptr::read_volatile((self.address + REGISTER_DATA) as *const u32)
+        }
+    }
+
+    fn write(&self, register: u32, value: u32) {
+        unsafe {
+            core::ptr::write_volatile((self.address + REGISTER_SELECT) as *mut u32, register);
+            core::ptr::write_volatile((self.address + REGISTER_DATA) as *mut u32, value);
+        }
+    }
+
+    fn handles(&self, gsi: u32) -> bool {
+        gsi >= self.gsi_base && gsi < self.gsi_base + self.entries
+    }
+
+    // Each entry is two registers, the low one has the vector and the flags
+    fn entry_register(&self, gsi: u32) -> u32 {
+        REDIRECTION_TABLE + (gsi - self.gsi_base) * 2
+    }
+}
+
+struct Routing {
+    io_apics: Vec<IoApic>,
+    // The global system interrupt of each ISA IRQ
+    isa_gsi: [u32; ISA_IRQS],
+}
+
+static ROUTING: Mutex<Routing> = Mutex::new(Routing {
+    io_apics: Vec::new(),
+    isa_gsi: [0; ISA_IRQS],
+});
+
+impl Routing {
+    fn io_apic(&self, gsi: u32) -> Option<&IoApic> {
+        self.io_apics.iter().find(|io_apic| io_apic.handles(gsi))
+    }
+
+    fn route(&self, gsi: u32, low: u32, destination: u8) {
+        if let Some(io_apic) = self.io_apic(gsi) {
+            let register = io_apic.entry_register(gsi);
+            // Masked while it's half written
+            io_apic.write(register, MASKED);
+            io_apic.write(register + 1, (destination as u32) << 24);
+            io_apic.write(register, low);
+        }
+    }
+}
+
+pub fn set_masked(irq: u8, masked: bool) {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let routing = ROUTING.lock();
+        let gsi = routing.isa_gsi[irq as usize];
+        if let Some(io_apic) = routing.io_apic(gsi) {
+            let register = io_apic.entry_register(gsi);
+            let low = io_apic.read(register);
+            if masked {
+                io_apic.write(register, low | MASKED);
+            } else {
+                io_apic.write(register, low & !MASKED);
+            }
+        }
+    });
+}
+
+// Must be called after the local APIC is initialized, interrupts are acknowledged there
+// The IRQs that were unmasked on the PICs stay unmasked, and the PICs get masked
+// Returns how many I/O APICs were found, the PICs stay in use if there are none
+pub fn init(
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<usize, MapToError<Size4KiB>> {
+    use x86_64::instructions::interrupts;
+
+    let madt = match acpi::find_table(b"APIC") {
+        Some(madt) => madt,
+        None => return Ok(0),
+    };
+    let header: acpi::SdtHeader = acpi::read(madt);
+
+    let mut routing = ROUTING.lock();
+    // ISA IRQs go to the same GSI unless there's an override
+    let mut isa_flags = [0u16; ISA_IRQS];
+    for (irq, gsi) in routing.isa_gsi.iter_mut().enumerate() {
+        *gsi = irq as u32;
+    }
+
+    let mut offset = MADT_ENTRIES;
+    while offset + 2 <= header.length as u64 {
+        let entry = madt + offset;
+        let kind: u8 = acpi::read(entry);
+        let length: u8 = acpi::read(entry + 1u64);
+        if length < 2 {
+            break;
+        }
+
+        match kind {
+            ENTRY_IOAPIC => {
+                let address: u32 = acpi::read(entry + 4u64);
+                let gsi_base: u32 = acpi::read(entry + 8u64);
+
+                let virtual_address = IOAPIC_START + routing.io_apics.len() * 4096;
+                let page = Page::containing_address(VirtAddr::new(virtual_address as u64));
+                let frame = PhysFrame::containing_address(PhysAddr::new(address as u64));
+                let flags =
+                    PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_CACHE;
+                // Only holding the mapper for this, the push below might have to grow the heap
+                memory::with_mapper(|mapper| unsafe {
+                    mapper
+                        .map_to(page, frame, flags, frame_allocator)
+                        .map(|flush| flush.flush())
+                })?;
+
+                let mut io_apic = IoApic {
+                    address: virtual_address,
+                    gsi_base,
+                    entries: 0,
+                };
+                io_apic.entries = (io_apic.read(VERSION) >> 16 & 0xFF) + 1;
+                for gsi in gsi_base..gsi_base + io_apic.entries {
+                    io_apic.write(io_apic.entry_register(gsi), MASKED);
+                }
+                routing.io_apics.push(io_apic);
+            }
+            ENTRY_OVERRIDE => {
+                let source: u8 = acpi::read(entry + 3u64);
+                let gsi: u32 = acpi::read(entry + 4u64);
+                let flags: u16 = acpi::read(entry + 8u64);
+                if (source as usize) < ISA_IRQS {
+                    routing.isa_gsi[source as usize] = gsi;
+                    isa_flags[source as usize] = flags;
+                }
+            }
+            _ => {}
+        }
+        offset += length as u64;
+    }
+
+    if routing.io_apics.is_empty() {
+        return Ok(0);
+    }
+
+    // Nothing can come in while half the IRQs are on the I/O APIC and half on the PICs,
+    // it would get acknowledged at the wrong place
+    let count = routing.io_apics.len();
+    interrupts::without_interrupts(|| {
+        let enabled = crate::interrupts::pic_enabled_irqs();
+        route_isa_irqs(&routing, isa_flags, enabled);
+        drop(routing);
+        crate::interrupts::use_apic_routing();
+    });
+    Ok(count)
+}
+
+fn route_isa_irqs(routing: &Routing, isa_flags: [u16; ISA_IRQS], enabled: u16) {
+    // Same vectors as with the PICs, so the IDT doesn't change
+    let destination = crate::apic::id();
+    for irq in 0..ISA_IRQS as u8 {
+        if irq == CASCADE_IRQ {
+            continue;
+        }
+        let flags = isa_flags[irq as usize];
+        let mut low = (PIC_1_OFFSET + irq) as u32;
+        // ISA IRQs are active high and edge triggered unless the override says otherwise
+        if flags & POLARITY_MASK == POLARITY_ACTIVE_LOW {
+            low |= ACTIVE_LOW;
+        }
+        if flags & TRIGGER_MASK == TRIGGER_LEVEL {
+            low |= LEVEL_TRIGGERED;
+        }
+        if enabled & (1 << irq) == 0 {
+            low |= MASKED;
+        }
+        routing.route(routing.isa_gsi[irq as usize], low, destination);
+    }
+}
@@ -0,0 +1,269 @@
+// PS/2 keyboard, pc_keyboard decodes the scancodes and the keymap picks the characters
+use crate::keymap;
+use crate::ps2::{self, Device, ACK, RESEND};
+use crate::vga_buffer::{self, BUFFER_HEIGHT};
+use lazy_static::lazy_static;
+use pc_keyboard::{layouts, DecodedKey, HandleControl, KeyCode, KeyState, Keyboard, ScancodeSet1};
+use spin::Mutex;
+
+pub const KEYBOARD_IRQ: u8 = 1;
+
+// Keyboard commands
+const SET_LEDS: u8 = 0xED;
+const ENABLE_SCANNING: u8 = 0xF4;
+
+// How many times a byte is sent again after a RESEND
+const LED_TRIES: u8 = 3;
+
+#[derive(Debug, Clone, Copy)]
+struct Modifiers {
+    left_shift: bool,
+    right_shift: bool,
+    left_control: bool,
+    right_control: bool,
+    alt: bool,
+    alt_gr: bool,
+    caps_lock: bool,
+    num_lock: bool,
+    scroll_lock: bool,
+}
+
+impl Modifiers {
+    fn shift(&self) -> bool {
+        self.left_shift || self.right_shift
+    }
+
+    fn control(&self) -> bool {
+        self.left_control || self.right_control
+    }
+
+    // Bits for the SET_LEDS command
+    fn leds(&self) -> u8 {
+        (self.scroll_lock as u8) | (self.num_lock as u8) << 1 | (self.caps_lock as u8) << 2
+    }
+}
+
+// Setting the LEDs takes two bytes, the keyboard acknowledges each one
+// The answers come in through the interrupt like scancodes do, nothing waits for them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LedUpdate {
+    Idle,
+    // SET_LEDS was sent, the LED bits go out when it's acknowledged
+    Command { tries: u8 },
+    Leds { tries: u8 },
+}
+
+struct State {
+    // Only used for decoding, its layout is never used
+    decoder: Keyboard<layouts::Us104Key, ScancodeSet1>,
+    modifiers: Modifiers,
+    leds: LedUpdate,
+    // A lock key was pressed while an update was still going
+    leds_changed: bool,
+}
+
+impl State {
+    // Returns true if the key was a modifier
+    fn update_modifiers(&mut self, code: KeyCode, state: KeyState) -> bool {
+        let down = state == KeyState::Down;
+        let modifiers = &mut self.modifiers;
+        match code {
+            KeyCode::ShiftLeft => modifiers.left_shift = down,
+            KeyCode::ShiftRight => modifiers.right_shift = down,
+            KeyCode::ControlLeft => modifiers.left_control = down,
+            KeyCode::ControlRight => modifiers.right_control = down,
+            KeyCode::AltLeft => modifiers.alt = down,
+            KeyCode::AltRight => modifiers.alt_gr = down,
+            KeyCode::CapsLock | KeyCode::NumpadLock | KeyCode::ScrollLock => {
+                if down {
+                    match code {
+                        KeyCode::CapsLock => modifiers.caps_lock = !modifiers.caps_lock,
+                        KeyCode::NumpadLock => modifiers.num_lock = !modifiers.num_lock,
+                        _ => modifiers.scroll_lock = !modifiers.scroll_lock,
+                    }
+                    self.update_leds();
+                }
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    fn update_leds(&mut self) {
+        if self.leds != LedUpdate::Idle {
+            self.leds_changed = true;
+            return;
+        }
+        self.leds_changed = false;
+        self.send_leds(LedUpdate::Command { tries: 0 });
+    }
+
+    fn send_leds(&mut self, step: LedUpdate) {
+        let byte = match step {
+            LedUpdate::Idle => return,
+            LedUpdate::Command { .. } => SET_LEDS,
+            LedUpdate::Leds { .. } => self.modifiers.leds(),
+        };
+        // Nothing to do about a keyboard that doesn't take the command
+        self.leds = match ps2::write(Device::Keyboard, byte) {
+            Ok(()) => step,
+            Err(_) => LedUpdate::Idle,
+        };
+    }
+
+    // An ACK or RESEND from the keyboard, for whichever LED byte was sent last
+    fn answer(&mut self, byte: u8) {
+        match (self.leds, byte) {
+            (LedUpdate::Command { .. }, ACK) => self.send_leds(LedUpdate::Leds { tries: 0 }),
+            (LedUpdate::Leds { .. }, ACK) => {
+                self.leds = LedUpdate::Idle;
+                if self.leds_changed {
+                    self.update_leds();
+                }
+            }
+            (LedUpdate::Command { tries }, RESEND) if tries + 1 < LED_TRIES => {
+                self.send_leds(LedUpdate::Command { tries: tries + 1 })
+            }
+            (LedUpdate::Leds { tries }, RESEND) if tries + 1 < LED_TRIES => {
+                self.send_leds(LedUpdate::Leds { tries: tries + 1 })
+            }
+            (LedUpdate::Idle, _) => {}
+            _ => self.leds = LedUpdate::Idle,
+        }
+    }
+
+    fn decode(&self, code: KeyCode) -> DecodedKey {
+        let modifiers = self.modifiers;
+        let numpad = |digit: char, key: KeyCode| {
+            if modifiers.num_lock {
+                DecodedKey::Unicode(digit)
+            } else {
+                DecodedKey::RawKey(key)
+            }
+        };
+
+        match code {
+            KeyCode::Tab => DecodedKey::Unicode('\t'),
+            KeyCode::Enter | KeyCode::NumpadEnter => DecodedKey::Unicode('\n'),
+            KeyCode::Backspace => DecodedKey::Unicode('\u{8}'),
+            KeyCode::Delete => DecodedKey::Unicode('\u{7f}'),
+            KeyCode::Escape => DecodedKey::Unicode('\u{1b}'),
+            KeyCode::NumpadSlash => DecodedKey::Unicode('/'),
+            KeyCode::NumpadStar => DecodedKey::Unicode('*'),
+            KeyCode::NumpadMinus => DecodedKey::Unicode('-'),
+            KeyCode::NumpadPlus => DecodedKey::Unicode('+'),
+            KeyCode::Numpad0 => numpad('0', KeyCode::Insert),
+            KeyCode::Numpad1 => numpad('1', KeyCode::End),
+            KeyCode::Numpad2 => numpad('2', KeyCode::ArrowDown),
+            KeyCode::Numpad3 => numpad('3', KeyCode::PageDown),
+            KeyCode::Numpad4 => numpad('4', KeyCode::ArrowLeft),
+            KeyCode::Numpad5 => numpad('5', KeyCode::Numpad5),
+            KeyCode::Numpad6 => numpad('6', KeyCode::ArrowRight),
+            KeyCode::Numpad7 => numpad('7', KeyCode::Home),
+            KeyCode::Numpad8 => numpad('8', KeyCode::ArrowUp),
+            KeyCode::Numpad9 => numpad('9', KeyCode::PageUp),
+            KeyCode::NumpadPeriod if modifiers.num_lock => DecodedKey::Unicode('.'),
+            KeyCode::NumpadPeriod => DecodedKey::Unicode('\u{7f}'),
+            // Ctrl+letter gives the matching control character, Ctrl+C is '\u{3}'
+            _ if modifiers.control() => match keymap::layout().map(code, false, false, false) {
+                Some(character @ '@'..='_') | Some(character @ 'a'..='z') => {
+                    DecodedKey::Unicode((character as u8 & 0x1f) as char)
+                }
+                _ => DecodedKey::RawKey(code),
+            },
+            _ => match keymap::layout().map(
+                code,
+                modifiers.shift(),
+                modifiers.caps_lock,
+                modifiers.alt_gr,
+            ) {
+                Some(character) => DecodedKey::Unicode(character),
+                None => DecodedKey::RawKey(code),
+            },
+        }
+    }
+}
+
+lazy_static! {
+    static ref KEYBOARD: Mutex<State> = Mutex::new(State {
+        decoder: Keyboard::new(layouts::Us104Key, ScancodeSet1, HandleControl::Ignore),
+        modifiers: Modifiers {
+            left_shift: false,
+            right_shift: false,
+            left_control: false,
+            right_control: false,
+            alt: false,
+            alt_gr: false,
+            caps_lock: false,
+            num_lock: true,
+            scroll_lock: false,
+        },
+        leds: LedUpdate::Idle,
+        leds_changed: false,
+    });
+}
+
+// Waits for the answers, only for init
+fn set_leds(leds: u8) -> Result<(), ps2::Error> {
+    ps2::send(Device::Keyboard, SET_LEDS)?;
+    ps2::send(Device::Keyboard, leds)
+}
+
+fn interrupt_handler() -> bool {
+    handle_scancode(ps2::read_output());
+    true
+}
+
+// Must be called after the PS/2 controller is initialized
+pub fn init() -> Result<(), ps2::Error> {
+    use x86_64::instructions::interrupts;
+
+    crate::interrupts::register(KEYBOARD_IRQ, "keyboard", interrupt_handler)
+        .expect("Keyboard IRQ is taken");
+
+    interrupts::without_interrupts(|| {
+        ps2::send(Device::Keyboard, ENABLE_SCANNING)?;
+        // The BIOS may have left the LEDs in a different state than ours
+        set_leds(KEYBOARD.lock().modifiers.leds())
+    })
+}
+
+pub fn handle_scancode(scancode: u8) {
+    // Answers to our own commands aren't keys
+    if scancode == ACK || scancode == RESEND {
+        KEYBOARD.lock().answer(scancode);
+        return;
+    }
+
+    // Don't hold the lock while the shell runs
+    let (key, modifiers) = {
+        let mut keyboard = KEYBOARD.lock();
+        let event = match keyboard.decoder.add_byte(scancode) {
+            Ok(Some(event)) => event,
+            _ => return,
+        };
+        if keyboard.update_modifiers(event.code, event.state) || event.state == KeyState::Up {
+            return;
+        }
+        (keyboard.decode(event.code), keyboard.modifiers)
+    };
+
+    // Shift+PageUp/PageDown scroll through the history, anything else goes back to the live screen
+    match key {
+        DecodedKey::RawKey(KeyCode::PageUp) if modifiers.shift() => {
+            vga_buffer::writer().scroll_up(BUFFER_HEIGHT - 1);
+        }
+        DecodedKey::RawKey(KeyCode::PageDown) if modifiers.shift() => {
+            vga_buffer::writer().scroll_down(BUFFER_HEIGHT - 1);
+        }
+        // Alt+F1..F4 switch between the virtual consoles
+        DecodedKey::RawKey(KeyCode::F1) if modifiers.alt => vga_buffer::switch_console(0),
+        DecodedKey::RawKey(KeyCode::F2) if modifiers.alt => vga_buffer::switch_console(1),
+        DecodedKey::RawKey(KeyCode::F3) if modifiers.alt => vga_buffer::switch_console(2),
+        DecodedKey::RawKey(KeyCode::F4) if modifiers.alt => vga_buffer::switch_console(3),
+        _ => {
+            vga_buffer::writer().reset_scroll();
+            crate::shell::handle_key(key);
+        }
+    }
+}
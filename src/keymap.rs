@@ -0,0 +1,318 @@
+// Keyboard layouts, mapping key codes to characters
+use core::sync::atomic::{AtomicUsize, Ordering};
+use pc_keyboard::KeyCode;
+
+// Each key lists the character it types normally, with shift and with AltGr
+// Missing characters mean the key doesn't type anything with that modifier
+type Keys = &'static [(KeyCode, &'static str)];
+
+pub struct Layout {
+    pub name: &'static str,
+    pub description: &'static str,
+    // Only the keys that are different from the US layout
+    keys: Keys,
+}
+
+impl Layout {
+    pub fn map(&self, code: KeyCode, shift: bool, caps_lock: bool, alt_gr: bool) -> Option<char> {
+        let characters = self
+            .keys
+            .iter()
+            .chain(US.iter())
+            .find(|(key, _)| *key == code)
+            .map(|&(_, characters)| characters)?;
+        let mut characters = characters.chars();
+        let normal = characters.next()?;
+        let shifted = characters.next();
+
+        if alt_gr {
+            return characters.next();
+        }
+
+        // Caps lock only affects keys that have an upper and lower case letter on them
+        let letter = normal.is_alphabetic() && shifted.map_or(false, char::is_alphabetic);
+        if shift != (caps_lock && letter) {
+            shifted
+        } else {
+            Some(normal)
+        }
+    }
+}
+
+// pc_keyboard calls the key above Enter BackSlash, and the extra key next to the left
+// shift on ISO keyboards HashTilde
+const US: Keys = &[
+    (KeyCode::BackTick, "`~"),
+    (KeyCode::Key1, "1!"),
+    (KeyCode::Key2, "2@"),
+    (KeyCode::Key3, "3#"),
+    (KeyCode::Key4, "4$"),
+    (KeyCode::Key5, "5%"),
+    (KeyCode::Key6, "6^"),
+    (KeyCode::Key7, "7&"),
+    (KeyCode::Key8, "8*"),
+    (KeyCode::Key9, "9("),
+    (KeyCode::Key0, "0)"),
+    (KeyCode::Minus, "-_"),
+    (KeyCode::Equals, "=+"),
+    (KeyCode::Q, "qQ"),
+    (KeyCode::W, "wW"),
+    (KeyCode::E, "eE"),
+    (KeyCode::R, "rR"),
+    (KeyCode::T, "tT"),
+    (KeyCode::Y, "yY"),
+    (KeyCode::U, "uU"),
+    (KeyCode::I, "iI"),
+    (KeyCode::O, "oO"),
+    (KeyCode::P, "pP"),
+    (KeyCode::BracketSquareLeft, "[{"),
+    (KeyCode::BracketSquareRight, "]}"),
+    (KeyCode::BackSlash, "\\|"),
+    (KeyCode::A, "aA"),
+    (KeyCode::S, "sS"),
+    (KeyCode::D, "dD"),
+    (KeyCode::F, "fF"),
+    (KeyCode::G, "gG"),
+    (KeyCode::H, "hH"),
+    (KeyCode::J, "jJ"),
+    (KeyCode::K, "kK"),
+    (KeyCode::L, "lL"),
+    (KeyCode::SemiColon, ";:"),
+    (KeyCode::Quote, "'\""),
+    (KeyCode::HashTilde, "\\|"),
+    (KeyCode::Z, "zZ"),
+    (KeyCode::X, "xX"),
+    (KeyCode::C, "cC"),
+    (KeyCode::V, "vV"),
+    (KeyCode::B, "bB"),
+    (KeyCode::N, "nN"),
+    (KeyCode::M, "mM"),
+    (KeyCode::Comma, ",<"),
+    (KeyCode::Fullstop, ".>"),
+    (KeyCode::Slash, "/?"),
+    (KeyCode::Spacebar, "  "),
+];
+
+const UK: Keys = &[
+    (KeyCode::BackTick, "`¬¦"),
+    (KeyCode::Key2, "2\""),
+    (KeyCode::Key3, "3£"),
+    (KeyCode::Key4, "4$€"),
+    (KeyCode::Quote, "'@"),
+    (KeyCode::BackSlash, "#~"),
+];
+
+// Dead keys (^ and ´) just type the accent
+const GERMAN: Keys = &[
+    (KeyCode::BackTick, "^°"),
+    (KeyCode::Key2, "2\"²"),
+    (KeyCode::Key3, "3§³"),
+    (KeyCode::Key6, "6&"),
+    (KeyCode::Key7, "7/{"),
+    (KeyCode::Key8, "8(["),
+    (KeyCode::Key9, "9)]"),
+    (KeyCode::Key0, "0=}"),
+    (KeyCode::Minus, "ß?\\"),
+    (KeyCode::Equals, "´`"),
+    (KeyCode::Q, "qQ@"),
+    (KeyCode::E, "eE€"),
+    (KeyCode::Y, "zZ"),
+    (KeyCode::BracketSquareLeft, "üÜ"),
+    (KeyCode::BracketSquareRight, "+*~"),
+    (KeyCode::BackSlash, "#'"),
+    (KeyCode::SemiColon, "öÖ"),
+    (KeyCode::Quote, "äÄ"),
+    (KeyCode::HashTilde, "<>|"),
+    (KeyCode::Z, "yY"),
+    (KeyCode::M, "mMµ"),
+    (KeyCode::Comma, ",;"),
+    (KeyCode::Fullstop, ".:"),
+    (KeyCode::Slash, "-_"),
+];
+
+const FRENCH: Keys = &[
+    (KeyCode::BackTick, "²"),
+    (KeyCode::Key1, "&1"),
+    (KeyCode::Key2, "é2~"),
+    (KeyCode::Key3, "\"3#"),
+    (KeyCode::Key4, "'4{"),
+    (KeyCode::Key5, "(5["),
+    (KeyCode::Key6, "-6|"),
+    (KeyCode::Key7, "è7`"),
+    (KeyCode::Key8, "_8\\"),
+    (KeyCode::Key9, "ç9^"),
+    (KeyCode::Key0, "à0@"),
+    (KeyCode::Minus, ")°]"),
+    (KeyCode::Equals, "=+}"),
+    (KeyCode::Q, "aA"),
+    (KeyCode::W, "zZ"),
+    (KeyCode::E, "eE€"),
+    (KeyCode::BracketSquareLeft, "^¨"),
+    (KeyCode::BracketSquareRight, "$£¤"),
+    (KeyCode::BackSlash, "*µ"),
+    (KeyCode::A, "qQ"),
+    (KeyCode::SemiColon, "mM"),
+    (KeyCode::Quote, "ù%"),
+    (KeyCode::HashTilde, "<>"),
+    (KeyCode::Z, "wW"),
+    (KeyCode::M, ",?"),
+    (KeyCode::Comma, ";."),
+    (KeyCode::Fullstop, ":/"),
+    (KeyCode::Slash, "!§"),
+];
+
+const DVORAK: Keys = &[
+    (KeyCode::Minus, "[{"),
+    (KeyCode::Equals, "]}"),
+    (KeyCode::Q, "'\""),
+    (KeyCode::W, ",<"),
+    (KeyCode::E, ".>"),
+    (KeyCode::R, "pP"),
+    (KeyCode::T, "yY"),
+    (KeyCode::Y, "fF"),
+    (KeyCode::U, "gG"),
+    (KeyCode::I, "cC"),
+    (KeyCode::O, "rR"),
+    (KeyCode::P, "lL"),
+    (KeyCode::BracketSquareLeft, "/?"),
+    (KeyCode::BracketSquareRight, "=+"),
+    (KeyCode::S, "oO"),
+    (KeyCode::D, "eE"),
+    (KeyCode::F, "uU"),
+    (KeyCode::G, "iI"),
+    (KeyCode::H, "dD"),
+    (KeyCode::J, "hH"),
+    (KeyCode::K, "tT"),
+    (KeyCode::L, "nN"),
+    (KeyCode::SemiColon, "sS"),
+    (KeyCode::Quote, "-_"),
+    (KeyCode::Z, ";:"),
+    (KeyCode::X, "qQ"),
+    (KeyCode::C, "jJ"),
+    (KeyCode::V, "kK"),
+    (KeyCode::B, "xX"),
+    (KeyCode::N, "bB"),
+    (KeyCode::Comma, "wW"),
+    (KeyCode::Fullstop, "vV"),
+    (KeyCode::Slash, "zZ"),
+];
+
+const COLEMAK: Keys = &[
+    (KeyCode::E, "fF"),
+    (KeyCode::R, "pP"),
+    (KeyCode::T, "gG"),
+    (KeyCode::Y, "jJ"),
+    (KeyCode::U, "lL"),
+    (KeyCode::I, "uU"),
+    (KeyCode::O, "yY"),
+    (KeyCode::P, ";:"),
+    (KeyCode::S, "rR"),
+    (KeyCode::D, "sS"),
+    (KeyCode::F, "tT"),
+    (KeyCode::G, "dD"),
+    (KeyCode::J, "nN"),
+    (KeyCode::K, "eE"),
+    (KeyCode::L, "iI"),
+    (KeyCode::SemiColon, "oO"),
+    (KeyCode::N, "kK"),
+];
+
+pub static LAYOUTS: [Layout; 6] = [
+    Layout {
+        name: "us",
+        description: "US English (QWERTY)",
+        keys: &[],
+    },
+    Layout {
+        name: "uk",
+        description: "UK English (QWERTY)",
+        keys: UK,
+    },
+    Layout {
+        name: "de",
+        description: "German (QWERTZ)",
+        keys: GERMAN,
+    },
+    Layout {
+        name: "fr",
+        description: "French (AZERTY)",
+        keys: FRENCH,
+    },
+    Layout {
+        name: "dvorak",
+        description: "US Dvorak",
+        keys: DVORAK,
+    },
+    Layout {
+        name: "colemak",
+        description: "US Colemak",
+        keys: COLEMAK,
+    },
+];
+
+// Index into LAYOUTS
+static CURRENT: AtomicUsize = AtomicUsize::new(0);
+
+pub fn layout() -> &'static Layout {
+    &LAYOUTS[CURRENT.load(Ordering::Relaxed)]
+}
+
+// Returns false if there is no layout with that name
+pub fn set_layout(name: &str) -> bool {
+    match LAYOUTS.iter().position(|layout| layout.name == name) {
+        Some(index) => {
+            CURRENT.store(index, Ordering::Relaxed);
+            true
+        }
+        None => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn named(name: &str) -> &'static Layout {
+        LAYOUTS.iter().find(|layout| layout.name == name).unwrap()
+    }
+
+    #[test_case]
+    fn shift_and_caps_lock() {
+        let us = named("us");
+        assert_eq!(us.map(KeyCode::A, false, false, false), Some('a'));
+        assert_eq!(us.map(KeyCode::A, true, false, false), Some('A'));
+        assert_eq!(us.map(KeyCode::A, false, true, false), Some('A'));
+        assert_eq!(us.map(KeyCode::A, true, true, false), Some('a'));
+    }
+
+    #[test_case]
+    fn caps_lock_leaves_other_keys_alone() {
+        let us = named("us");
+        assert_eq!(us.map(KeyCode::Key1, false, true, false), Some('1'));
+        assert_eq!(us.map(KeyCode::Key1, true, true, false), Some('!'));
+    }
+
+    #[test_case]
+    fn layouts_fall_back_to_us() {
+        let german = named("de");
+        assert_eq!(german.map(KeyCode::Y, false, false, false), Some('z'));
+        assert_eq!(german.map(KeyCode::Key2, true, false, false), Some('"'));
+        assert_eq!(german.map(KeyCode::X, false, false, false), Some('x'));
+    }
+
+    #[test_case]
+    fn alt_gr() {
+        let german = named("de");
+        assert_eq!(german.map(KeyCode::Q, false, false, true), Some('@'));
+        assert_eq!(german.map(KeyCode::W, false, false, true), None);
+    }
+
+    #[test_case]
+    fn set_layout_by_name() {
+        assert!(set_layout("fr"));
+        assert_eq!(layout().name, "fr");
+        assert!(!set_layout("klingon"));
+        assert_eq!(layout().name, "fr");
+        assert!(set_layout("us"));
+    }
+}
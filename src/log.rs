@@ -0,0 +1,347 @@
+// Kernel log with levels, per-module filters and a ring buffer for dmesg
+use crate::clock::{uptime, Seconds};
+use crate::console::with_console;
+use crate::rtc::DateTime;
+use crate::serial::SERIAL1;
+use alloc::collections::VecDeque;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt;
+use core::time::Duration;
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+// The oldest messages are dropped once the buffer is full
+const BUFFER_ENTRIES: usize = 512;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error = 1,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    pub const ALL: [Level; 5] = [
+        Level::Error,
+        Level::Warn,
+        Level::Info,
+        Level::Debug,
+        Level::Trace,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+            Level::Trace => "trace",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Level> {
+        Level::ALL
+            .iter()
+            .copied()
+            .find(|level| level.name() == name)
+    }
+
+    // ANSI color of the level tag
+    fn color(self) -> u8 {
+        match self {
+            Level::Error => 91,
+            Level::Warn => 93,
+            Level::Info => 92,
+            Level::Debug => 96,
+            Level::Trace => 37,
+        }
+    }
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let tag = match self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+            Level::Trace => "TRACE",
+        };
+        f.pad(tag)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Sink {
+    Screen,
+    Serial,
+}
+
+pub struct Entry {
+    // Since boot
+    pub time: Duration,
+    pub level: Level,
+    pub module: &'static str,
+    pub message: String,
+}
+
+impl fmt::Display for Entry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write_line(
+            f,
+            &format_args!("{:>9}", Seconds(self.time)),
+            self.level,
+            self.module,
+            format_args!("{}", self.message),
+        )
+    }
+}
+
+// Shows an entry with the date and time instead of the seconds since boot, for dmesg -T
+pub struct WallClock<'a>(pub &'a Entry);
+
+impl fmt::Display for WallClock<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let entry = self.0;
+        let time = DateTime::from_unix(crate::clock::boot_time() + entry.time.as_secs());
+        write_line(
+            f,
+            &time,
+            entry.level,
+            entry.module,
+            format_args!("{}", entry.message),
+        )
+    }
+}
+
+// Every message looks like "[    1.234] INFO  ata: ..."
+fn write_line<W: fmt::Write + ?Sized>(
+    out: &mut W,
+    time: &dyn fmt::Display,
+    level: Level,
+    module: &str,
+    message: fmt::Arguments,
+) -> fmt::Result {
+    write!(
+        out,
+        "[{}] \x1b[{}m{:5}\x1b[0m {}: {}",
+        time,
+        level.color(),
+        level,
+        module,
+        message
+    )
+}
+
+struct Logger {
+    level: Level,
+    // Module names without the crate name, e.g. "ata"
+    filters: Vec<(String, Level)>,
+    // Stays None until there is a heap to put messages in
+    buffer: Option<VecDeque<Entry>>,
+    screen: bool,
+    serial: bool,
+}
+
+impl Logger {
+    // The most specific filter wins, "ata" also covers "ata::something"
+    fn level_for(&self, module: &str) -> Level {
+        self.filters
+            .iter()
+            .filter(|(name, _)| {
+                module == name
+                    || (module.starts_with(name.as_str()) && module[name.len()..].starts_with("::"))
+            })
+            .max_by_key(|(name, _)| name.len())
+            .map_or(self.level, |&(_, level)| level)
+    }
+}
+
+lazy_static! {
+    static ref LOGGER: Mutex<Logger> = Mutex::new(Logger {
+        level: Level::Info,
+        filters: Vec::new(),
+        buffer: None,
+        screen: true,
+        serial: true,
+    });
+}
+
+// Must be called AFTER the heap is initialized, messages from before that aren't kept for dmesg
+pub fn init() {
+    interrupts::without_interrupts(|| {
+        LOGGER.lock().buffer = Some(VecDeque::new());
+    });
+}
+
+#[macro_export]
+macro_rules! log {
+    ($level:expr, $($arg:tt)*) => ($crate::log::_log($level, module_path!(), format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)*) => ($crate::log!($crate::log::Level::Error, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)*) => ($crate::log!($crate::log::Level::Warn, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)*) => ($crate::log!($crate::log::Level::Info, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)*) => ($crate::log!($crate::log::Level::Debug, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! trace {
+    ($($arg:tt)*) => ($crate::log!($crate::log::Level::Trace, $($arg)*));
+}
+
+#[doc(hidden)]
+pub fn _log(level: Level, module_path: &'static str, args: fmt::Arguments) {
+    // Drop the crate name, anything logged from main.rs is just "kernel"
+    let module = module_path.splitn(2, "::").nth(1).unwrap_or("kernel");
+    let time = uptime();
+
+    // Only the settings are read under the lock, formatting the message could log too
+    let (enabled, screen, serial, buffered) = interrupts::without_interrupts(|| {
+        let logger = LOGGER.lock();
+        (
+            level <= logger.level_for(module),
+            logger.screen,
+            logger.serial,
+            logger.buffer.is_some(),
+        )
+    });
+    if !enabled {
+        return;
+    }
+
+    // Formatted once if there's a heap to keep it in, the outputs write the entry then
+    let entry = if buffered {
+        Some(Entry {
+            time,
+            level,
+            module,
+            message: args.to_string(),
+        })
+    } else {
+        None
+    };
+
+    // Not through print!, it would end up on the serial port whether it's enabled or not
+    if screen {
+        with_console(|console| write_message(console, &entry, time, level, module, args).unwrap());
+    }
+    if serial {
+        interrupts::without_interrupts(|| {
+            let mut serial = SERIAL1.lock();
+            write_message(&mut *serial, &entry, time, level, module, args).unwrap();
+        });
+    }
+
+    if let Some(entry) = entry {
+        interrupts::without_interrupts(|| {
+            if let Some(buffer) = LOGGER.lock().buffer.as_mut() {
+                if buffer.len() == BUFFER_ENTRIES {
+                    buffer.pop_front();
+                }
+                buffer.push_back(entry);
+            }
+        });
+    }
+}
+
+fn write_message<W: fmt::Write + ?Sized>(
+    out: &mut W,
+    entry: &Option<Entry>,
+    time: Duration,
+    level: Level,
+    module: &str,
+    args: fmt::Arguments,
+) -> fmt::Result {
+    match entry {
+        Some(entry) => writeln!(out, "{}", entry),
+        None => {
+            write_line(
+                out,
+                &format_args!("{:>9}", Seconds(time)),
+                level,
+                module,
+                args,
+            )?;
+            out.write_str("\n")
+        }
+    }
+}
+
+// Runs f on every buffered message, oldest first
+pub fn for_each_entry<F: FnMut(&Entry)>(mut f: F) {
+    interrupts::without_interrupts(|| {
+        if let Some(buffer) = LOGGER.lock().buffer.as_ref() {
+            buffer.iter().for_each(|entry| f(entry));
+        }
+    });
+}
+
+pub fn clear() {
+    interrupts::without_interrupts(|| {
+        if let Some(buffer) = LOGGER.lock().buffer.as_mut() {
+            buffer.clear();
+        }
+    });
+}
+
+// Without a module this sets the level for every module that has no filter of its own
+pub fn set_level(module: Option<&str>, level: Level) {
+    interrupts::without_interrupts(|| {
+        let mut logger = LOGGER.lock();
+        match module {
+            Some(module) => {
+                logger.filters.retain(|(name, _)| name != module);
+                logger.filters.push((module.to_string(), level));
+            }
+            None => logger.level = level,
+        }
+    });
+}
+
+pub fn set_sink(sink: Sink, enabled: bool) {
+    interrupts::without_interrupts(|| {
+        let mut logger = LOGGER.lock();
+        match sink {
+            Sink::Screen => logger.screen = enabled,
+            Sink::Serial => logger.serial = enabled,
+        }
+    });
+}
+
+pub struct Settings {
+    pub level: Level,
+    pub filters: Vec<(String, Level)>,
+    pub screen: bool,
+    pub serial: bool,
+}
+
+pub fn settings() -> Settings {
+    interrupts::without_interrupts(|| {
+        let logger = LOGGER.lock();
+        Settings {
+            level: logger.level,
+            filters: logger.filters.clone(),
+            screen: logger.screen,
+            serial: logger.serial,
+        }
+    })
+}
@@ -7,13 +7,36 @@
 #![feature(abi_x86_interrupt)]
 #![feature(alloc_error_handler)]
 
+mod acpi;
 mod allocator;
+mod ansi;
+mod apic;
 mod ata;
+mod backtrace;
 mod clock;
+mod console;
+mod cp437;
+mod exceptions;
+mod framebuffer;
+mod frames;
 mod gdt;
+mod graphics;
+mod hpet;
 mod interrupts;
+mod ioapic;
+mod keyboard;
+mod keymap;
+mod log;
 mod memory;
+mod mouse;
+mod pci;
+mod ps2;
+mod psf;
+mod rtc;
+mod serial;
 mod shell;
+mod stack;
+mod timer;
 mod vga_buffer;
 
 use bootloader::entry_point;
@@ -25,6 +48,7 @@ extern crate alloc;
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
     println!("{}", info);
+    backtrace::print_on_panic();
     loop {}
 }
 
@@ -42,40 +66,134 @@ fn init() {
     x86_64::instructions::interrupts::enable();
 }
 
-macro_rules! status {
-    ($n:expr) => {
-        print!("[ ");
-        change_color(Color::Green, Color::Black);
-        print!("OK");
-        change_color(Color::White, Color::Black);
-        println!(" ] {}", $n);
-    };
-}
-
 entry_point!(main);
 
 fn main(boot_info: &'static BootInfo) -> ! {
-    use crate::vga_buffer::{change_color, Color};
-    use memory::BootInfoFrameAllocator;
-    use x86_64::VirtAddr; // For status! macro
+    use x86_64::VirtAddr;
 
     init();
-    status!("Initialized GDT and Interrupts");
+    info!("Initialized GDT and Interrupts");
+
+    serial::init();
+    info!("Initialized serial port");
 
     clock::init();
-    status!("Initialized system clock");
+    info!(
+        "Initialized system clock from the {}, TSC at {} MHz",
+        clock::source(),
+        clock::tsc_frequency() / 1_000_000
+    );
+
+    let now = rtc::init();
+    info!("Initialized real-time clock: {} UTC", now);
 
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
-    let mut mapper = unsafe { memory::init(phys_mem_offset) };
-    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
-    status!("Initialized Mapper and Frame allocator");
+    unsafe { memory::init(phys_mem_offset) };
+    frames::init(&boot_info.memory_map);
+    let mut frame_allocator = frames::Allocator;
+    info!(
+        "Initialized Mapper and Frame allocator, {} MiB usable",
+        frames::stats().usable / 256
+    );
+
+    memory::with_mapper(|mapper| allocator::init_heap(mapper, &mut frame_allocator))
+        .expect("Heap initialization failed");
+    log::init();
+    info!("Initialized heap");
+
+    // The bootloader's stack and the early double fault stack have no guard pages
+    let double_fault_stack = stack::allocate(
+        "double fault",
+        stack::DOUBLE_FAULT_STACK_PAGES,
+        &mut frame_allocator,
+    )
+    .expect("Double fault stack allocation failed");
+    unsafe { gdt::set_interrupt_stack(gdt::DOUBLE_FAULT_IST_INDEX, double_fault_stack.top) };
+    let kernel_stack = stack::allocate("kernel", stack::KERNEL_STACK_PAGES, &mut frame_allocator)
+        .expect("Kernel stack allocation failed");
+    info!("Switching to guarded kernel stacks");
+
+    stack::switch_to(&kernel_stack, move || run(frame_allocator))
+}
+
+// The rest of main, on the new kernel stack
+fn run(mut frame_allocator: frames::Allocator) -> ! {
+    use crate::console::change_color;
+    use crate::vga_buffer::Color;
+    use alloc::string::String;
+
+    vga_buffer::init_scrollback();
+    info!("Initialized scrollback buffer");
+
+    match acpi::init() {
+        Some(revision) => {
+            let mut tables = String::new();
+            acpi::for_each_table(|_, header| {
+                tables.push_str(core::str::from_utf8(&header.signature).unwrap_or("????"));
+                tables.push(' ');
+            });
+            info!("Found ACPI {} tables: {}", revision, tables.trim_end());
+        }
+        None => warn!("No ACPI tables"),
+    }
+
+    if memory::with_mapper(|mapper| hpet::init(mapper, &mut frame_allocator))
+        .expect("HPET mapping failed")
+    {
+        clock::use_hpet();
+        info!(
+            "Initialized HPET at {} MHz, the clock is on the {}",
+            hpet::frequency() / 1_000_000,
+            clock::source()
+        );
+    } else {
+        info!("No HPET, the clock stays on the {}", clock::source());
+    }
+
+    match memory::with_mapper(|mapper| apic::init(mapper, &mut frame_allocator))
+        .expect("Local APIC mapping failed")
+    {
+        Some(mode) => {
+            info!("Initialized local APIC timer ({})", mode);
+            match ioapic::init(&mut frame_allocator).expect("I/O APIC mapping failed") {
+                0 => info!("No I/O APIC, staying on the PICs"),
+                count => info!("Initialized {} I/O APIC(s), the PICs are masked", count),
+            }
+        }
+        None => info!("No usable local APIC timer, the PIT keeps the time"),
+    }
+
+    if memory::with_mapper(|mapper| framebuffer::init(mapper, &mut frame_allocator))
+        .expect("Framebuffer mapping failed")
+    {
+        info!("Found linear framebuffer");
+    }
 
-    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("Heap initialization failed");
-    status!("Initialized heap");
+    match ps2::init() {
+        Ok(has_mouse_port) => {
+            info!("Initialized PS/2 controller");
+            if let Err(error) = keyboard::init() {
+                warn!("Keyboard setup failed: {:?}", error);
+            }
+            if has_mouse_port {
+                match mouse::init() {
+                    Ok(true) => info!("Initialized PS/2 mouse with scroll wheel"),
+                    Ok(false) => info!("Initialized PS/2 mouse"),
+                    Err(error) => warn!("No PS/2 mouse: {:?}", error),
+                }
+            }
+        }
+        Err(error) => error!("PS/2 controller initialization failed: {:?}", error),
+    }
 
+    ata::init();
     // Must be initialized AFTER the heap!
-    println!("{:#?}", ata::info());
-    println!("{:#?}", ata::info());
+    for (drive, model, serial_number, size, unit) in ata::info() {
+        info!(
+            "ATA drive {}: {} (serial {}), {} {}",
+            drive, model, serial_number, size, unit
+        );
+    }
 
     println!();
     print!("Welcome to ");
@@ -84,12 +202,16 @@ fn main(boot_info: &'static BootInfo) -> ! {
     change_color(Color::White, Color::Black);
 
     // First prompt, future prompts will be handled by shell::evaluate
-    print!(">>> ");
+    print!("{}", shell::PROMPT);
+    for console in 1..vga_buffer::CONSOLES {
+        vga_buffer::print_to(console, format_args!("{}", shell::PROMPT));
+    }
 
     #[cfg(test)]
     test_main();
 
     loop {
+        shell::run_pending();
         // Halt CPU so that usage isn't 100% all the time
         x86_64::instructions::hlt();
     }
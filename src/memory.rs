@@ -1,12 +1,41 @@
-use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
 use x86_64::{
-    structures::paging::{FrameAllocator, OffsetPageTable, PageTable, PhysFrame, Size4KiB},
+    structures::paging::{OffsetPageTable, PageTable},
     PhysAddr, VirtAddr,
 };
 
-pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
+// Where the bootloader mapped all of physical memory
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+// The only handle on the page tables, boot code and the growing heap both map pages with it
+static MAPPER: Mutex<Option<OffsetPageTable<'static>>> = Mutex::new(None);
+
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    VirtAddr::new(PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed) + addr.as_u64())
+}
+
+pub unsafe fn init(physical_memory_offset: VirtAddr) {
+    PHYSICAL_MEMORY_OFFSET.store(physical_memory_offset.as_u64(), Ordering::Relaxed);
     let level_4_table = active_level_4_table(physical_memory_offset);
-    OffsetPageTable::new(level_4_table, physical_memory_offset)
+    let mapper = OffsetPageTable::new(level_4_table, physical_memory_offset);
+    without_interrupts(|| *MAPPER.lock() = Some(mapper));
+}
+
+// Interrupts stay on, some of the boot code waits for timer ticks while it holds the mapper
+// The heap can't grow while f runs, so f shouldn't need much from it
+pub fn with_mapper<T>(f: impl FnOnce(&mut OffsetPageTable<'static>) -> T) -> T {
+    f(MAPPER
+        .lock()
+        .as_mut()
+        .expect("mapper used before memory::init"))
+}
+
+// For the heap, which can be called from inside with_mapper or an interrupt handler
+// Gives up instead of waiting, whoever holds the mapper can't carry on until the heap returns
+pub fn try_with_mapper<T>(f: impl FnOnce(&mut OffsetPageTable<'static>) -> T) -> Option<T> {
+    MAPPER.try_lock()?.as_mut().map(f)
 }
 
 unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut PageTable {
@@ -20,33 +49,3 @@ unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut
 
     &mut *page_table_ptr
 }
-
-pub struct BootInfoFrameAllocator {
-    memory_map: &'static MemoryMap,
-    next: usize,
-}
-
-impl BootInfoFrameAllocator {
-    pub unsafe fn init(memory_map: &'static MemoryMap) -> Self {
-        BootInfoFrameAllocator {
-            memory_map,
-            next: 0,
-        }
-    }
-
-    fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
-        let regions = self.memory_map.iter();
-        let usable_regions = regions.filter(|r| r.region_type == MemoryRegionType::Usable);
-        let addr_ranges = usable_regions.map(|r| r.range.start_addr()..r.range.end_addr());
-        let frame_addresses = addr_ranges.flat_map(|r| r.step_by(4096));
-        frame_addresses.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
-    }
-}
-
-unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
-    fn allocate_frame(&mut self) -> Option<PhysFrame> {
-        let frame = self.usable_frames().nth(self.next);
-        self.next += 1;
-        frame
-    }
-}
@@ -0,0 +1,222 @@
+// PS/2 mouse, shows a cursor in VGA text mode for selecting and pasting text
+use crate::ps2::{self, Device};
+use crate::vga_buffer::{self, BUFFER_HEIGHT, BUFFER_WIDTH};
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+pub const MOUSE_IRQ: u8 = 12;
+
+// Mouse commands
+const GET_ID: u8 = 0xF2;
+const SET_SAMPLE_RATE: u8 = 0xF3;
+const ENABLE_REPORTING: u8 = 0xF4;
+const SET_DEFAULTS: u8 = 0xF6;
+
+// Mice with a scroll wheel answer GET_ID with this after the sample rate trick in init
+const WHEEL_MOUSE_ID: u8 = 3;
+
+// How far the mouse has to move to go to the next character
+const STEPS_PER_COLUMN: i32 = 8;
+const STEPS_PER_ROW: i32 = 16;
+
+// Events nobody picked up are dropped after a while
+const MAX_EVENTS: usize = 64;
+
+// How many lines one click of the scroll wheel scrolls
+const SCROLL_LINES: usize = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MouseEvent {
+    pub dx: i16,
+    // Positive is up
+    pub dy: i16,
+    // Positive is towards the user
+    pub scroll: i8,
+    pub left: bool,
+    pub right: bool,
+    pub middle: bool,
+}
+
+struct Mouse {
+    packet: [u8; 4],
+    received: usize,
+    // 4 with a scroll wheel, 3 without
+    packet_size: usize,
+    events: VecDeque<MouseEvent>,
+    // Position in steps, not characters
+    x: i32,
+    y: i32,
+    buttons: (bool, bool),
+    // Where the left button was pressed, as (row, column)
+    anchor: (usize, usize),
+    clipboard: String,
+}
+
+impl Mouse {
+    fn add_byte(&mut self, byte: u8) -> Option<MouseEvent> {
+        // Bit 3 is always set in the first byte, if it isn't we're out of sync
+        if self.received == 0 && byte & 0x08 == 0 {
+            return None;
+        }
+
+        self.packet[self.received] = byte;
+        self.received += 1;
+        if self.received < self.packet_size {
+            return None;
+        }
+        self.received = 0;
+
+        let flags = self.packet[0];
+        // The movement overflowed, there's nothing useful in the packet
+        if flags & 0xC0 != 0 {
+            return None;
+        }
+
+        // Bits 4 and 5 are the sign bits of the movement
+        let dx = self.packet[1] as i16 - ((flags as i16) << 4 & 0x100);
+        let dy = self.packet[2] as i16 - ((flags as i16) << 3 & 0x100);
+        let scroll = if self.packet_size == 4 {
+            // Only the low 4 bits are the scroll wheel
+            ((self.packet[3] << 4) as i8) >> 4
+        } else {
+            0
+        };
+
+        Some(MouseEvent {
+            dx,
+            dy,
+            scroll,
+            left: flags & 0x01 != 0,
+            right: flags & 0x02 != 0,
+            middle: flags & 0x04 != 0,
+        })
+    }
+
+    // The character under the cursor, as (row, column)
+    fn cell(&self) -> (usize, usize) {
+        (
+            (self.y / STEPS_PER_ROW) as usize,
+            (self.x / STEPS_PER_COLUMN) as usize,
+        )
+    }
+}
+
+lazy_static! {
+    static ref MOUSE: Mutex<Mouse> = Mutex::new(Mouse {
+        packet: [0; 4],
+        received: 0,
+        packet_size: 3,
+        events: VecDeque::new(),
+        x: BUFFER_WIDTH as i32 * STEPS_PER_COLUMN / 2,
+        y: BUFFER_HEIGHT as i32 * STEPS_PER_ROW / 2,
+        buttons: (false, false),
+        anchor: (0, 0),
+        clipboard: String::new(),
+    });
+}
+
+// Must be called AFTER the heap and the PS/2 controller are initialized
+// Returns whether the mouse has a scroll wheel
+pub fn init() -> Result<bool, ps2::Error> {
+    use x86_64::instructions::interrupts;
+
+    let wheel = interrupts::without_interrupts(|| {
+        ps2::send(Device::Mouse, SET_DEFAULTS)?;
+
+        // Setting these sample rates in a row turns on the scroll wheel
+        for &rate in &[200, 100, 80] {
+            ps2::send(Device::Mouse, SET_SAMPLE_RATE)?;
+            ps2::send(Device::Mouse, rate)?;
+        }
+        ps2::send(Device::Mouse, GET_ID)?;
+        let wheel = ps2::receive(Device::Mouse)? == WHEEL_MOUSE_ID;
+
+        ps2::send(Device::Mouse, ENABLE_REPORTING)?;
+        MOUSE.lock().packet_size = if wheel { 4 } else { 3 };
+        Ok(wheel)
+    })?;
+
+    ps2::enable_mouse_interrupts()?;
+    crate::interrupts::register(MOUSE_IRQ, "mouse", interrupt_handler).expect("Mouse IRQ is taken");
+    Ok(wheel)
+}
+
+// Oldest first
+#[allow(dead_code)]
+pub fn next_event() -> Option<MouseEvent> {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| MOUSE.lock().events.pop_front())
+}
+
+fn interrupt_handler() -> bool {
+    handle_byte(ps2::read_output());
+    true
+}
+
+pub fn handle_byte(byte: u8) {
+    let mut mouse = MOUSE.lock();
+    let event = match mouse.add_byte(byte) {
+        Some(event) => event,
+        None => return,
+    };
+    if mouse.events.len() == MAX_EVENTS {
+        mouse.events.pop_front();
+    }
+    mouse.events.push_back(event);
+
+    // The cursor only works on the VGA text consoles
+    // try_lock, because a command could be holding the lock while it draws
+    let text_mode = crate::graphics::GRAPHICS
+        .try_lock()
+        .map_or(false, |graphics| {
+            graphics.mode() == crate::graphics::Mode::Text
+        });
+    if !text_mode || crate::console::framebuffer_enabled() {
+        return;
+    }
+
+    let max_x = BUFFER_WIDTH as i32 * STEPS_PER_COLUMN - 1;
+    let max_y = BUFFER_HEIGHT as i32 * STEPS_PER_ROW - 1;
+    mouse.x = (mouse.x + event.dx as i32).max(0).min(max_x);
+    mouse.y = (mouse.y - event.dy as i32).max(0).min(max_y);
+    let cell = mouse.cell();
+
+    let (was_left, was_middle) = mouse.buttons;
+    mouse.buttons = (event.left, event.middle);
+    if event.left && !was_left {
+        mouse.anchor = cell;
+    }
+    let anchor = mouse.anchor;
+    // The middle button types whatever was copied, the shell picks it up from the main loop
+    if event.middle && !was_middle {
+        crate::shell::paste(&mouse.clipboard);
+    }
+    // Let go of the mouse before taking the writer, the two locks are never held together
+    drop(mouse);
+
+    let mut writer = vga_buffer::writer();
+    let lines = SCROLL_LINES * event.scroll.abs() as usize;
+    if event.scroll < 0 {
+        writer.scroll_up(lines);
+    } else if event.scroll > 0 {
+        writer.scroll_down(lines);
+    }
+    writer.set_mouse_cursor(Some(cell));
+
+    // Dragging with the left button selects, letting go copies the selection
+    if event.left {
+        writer.set_selection(Some((anchor, cell)));
+    } else if was_left {
+        // Just clicking doesn't select anything
+        if cell == anchor {
+            writer.set_selection(None);
+        } else {
+            let text = writer.selected_text();
+            drop(writer);
+            MOUSE.lock().clipboard = text;
+        }
+    }
+}
@@ -0,0 +1,58 @@
+// PCI configuration space access through the legacy I/O ports
+use x86_64::instructions::port::Port;
+
+const CONFIG_ADDRESS: u16 = 0xCF8;
+const CONFIG_DATA: u16 = 0xCFC;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Device {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl Device {
+    pub fn read(&self, offset: u8) -> u32 {
+        let address = 0x8000_0000
+            | (self.bus as u32) << 16
+            | (self.device as u32) << 11
+            | (self.function as u32) << 8
+            | (offset as u32 & 0xFC);
+        let mut address_port: Port<u32> = Port::new(CONFIG_ADDRESS);
+        let mut data_port: Port<u32> = Port::new(CONFIG_DATA);
+        unsafe {
+            address_port.write(address);
+            data_port.read()
+        }
+    }
+
+    pub fn vendor_id(&self) -> u16 {
+        self.read(0x00) as u16
+    }
+
+    pub fn device_id(&self) -> u16 {
+        (self.read(0x00) >> 16) as u16
+    }
+
+    // Base address of a memory BAR, without the flag bits
+    pub fn bar(&self, index: u8) -> u32 {
+        self.read(0x10 + index * 4) & !0xF
+    }
+}
+
+// Only looks at function 0 of each device
+pub fn find(vendor_id: u16, device_id: u16) -> Option<Device> {
+    for bus in 0..=255 {
+        for device in 0..32 {
+            let candidate = Device {
+                bus,
+                device,
+                function: 0,
+            };
+            if candidate.vendor_id() == vendor_id && candidate.device_id() == device_id {
+                return Some(candidate);
+            }
+        }
+    }
+    None
+}
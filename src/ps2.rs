@@ -0,0 +1,206 @@
+// 8042 PS/2 controller, the keyboard is on the first port and the mouse on the second
+use core::hint::spin_loop;
+use x86_64::instructions::port::Port;
+
+const DATA_PORT: u16 = 0x60;
+// Reading gives the status, writing sends a command to the controller
+const COMMAND_PORT: u16 = 0x64;
+
+// Status bits
+const OUTPUT_FULL: u8 = 0x01;
+const INPUT_FULL: u8 = 0x02;
+const MOUSE_DATA: u8 = 0x20;
+
+// Controller commands
+const READ_CONFIG: u8 = 0x20;
+const WRITE_CONFIG: u8 = 0x60;
+const DISABLE_MOUSE_PORT: u8 = 0xA7;
+const ENABLE_MOUSE_PORT: u8 = 0xA8;
+const TEST_MOUSE_PORT: u8 = 0xA9;
+const SELF_TEST: u8 = 0xAA;
+const TEST_KEYBOARD_PORT: u8 = 0xAB;
+const DISABLE_KEYBOARD_PORT: u8 = 0xAD;
+const ENABLE_KEYBOARD_PORT: u8 = 0xAE;
+const WRITE_MOUSE_PORT: u8 = 0xD4;
+
+// Configuration bits
+const KEYBOARD_IRQ: u8 = 0x01;
+const MOUSE_IRQ: u8 = 0x02;
+const MOUSE_CLOCK_DISABLED: u8 = 0x20;
+
+// Answers from the devices
+pub const ACK: u8 = 0xFA;
+pub const RESEND: u8 = 0xFE;
+
+// How many times we check the status before giving up
+const TIMEOUT: usize = 100_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Device {
+    Keyboard,
+    Mouse,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Error {
+    Timeout,
+    SelfTestFailed(u8),
+    PortTestFailed(Device, u8),
+    NoAck(u8),
+}
+
+fn status() -> u8 {
+    let mut port: Port<u8> = Port::new(COMMAND_PORT);
+    unsafe { port.read() }
+}
+
+fn wait_for(ready: impl Fn(u8) -> bool) -> Result<(), Error> {
+    for _ in 0..TIMEOUT {
+        if ready(status()) {
+            return Ok(());
+        }
+        spin_loop();
+    }
+    Err(Error::Timeout)
+}
+
+fn write_command(command: u8) -> Result<(), Error> {
+    wait_for(|status| status & INPUT_FULL == 0)?;
+    let mut port: Port<u8> = Port::new(COMMAND_PORT);
+    unsafe { port.write(command) };
+    Ok(())
+}
+
+fn write_data(byte: u8) -> Result<(), Error> {
+    wait_for(|status| status & INPUT_FULL == 0)?;
+    let mut port: Port<u8> = Port::new(DATA_PORT);
+    unsafe { port.write(byte) };
+    Ok(())
+}
+
+fn read_data() -> Result<u8, Error> {
+    wait_for(|status| status & OUTPUT_FULL != 0)?;
+    let mut port: Port<u8> = Port::new(DATA_PORT);
+    Ok(unsafe { port.read() })
+}
+
+// Throw away anything left over in the output buffer
+fn flush() {
+    let mut port: Port<u8> = Port::new(DATA_PORT);
+    while status() & OUTPUT_FULL != 0 {
+        unsafe { port.read() };
+    }
+}
+
+fn read_config() -> Result<u8, Error> {
+    write_command(READ_CONFIG)?;
+    read_data()
+}
+
+fn write_config(config: u8) -> Result<(), Error> {
+    write_command(WRITE_CONFIG)?;
+    write_data(config)
+}
+
+// Sends a byte to a device and waits for it to be acknowledged
+pub fn send(device: Device, byte: u8) -> Result<(), Error> {
+    for _ in 0..3 {
+        if device == Device::Mouse {
+            write_command(WRITE_MOUSE_PORT)?;
+        }
+        write_data(byte)?;
+        match receive(device)? {
+            ACK => return Ok(()),
+            RESEND => continue,
+            answer => return Err(Error::NoAck(answer)),
+        }
+    }
+    Err(Error::NoAck(RESEND))
+}
+
+// Sends a byte without waiting for the answer, for interrupt handlers that get it as an interrupt
+pub fn write(device: Device, byte: u8) -> Result<(), Error> {
+    if device == Device::Mouse {
+        write_command(WRITE_MOUSE_PORT)?;
+    }
+    write_data(byte)
+}
+
+// Waits for a byte from the device, bytes from the other device are thrown away
+pub fn receive(device: Device) -> Result<u8, Error> {
+    let mut port: Port<u8> = Port::new(DATA_PORT);
+    for _ in 0..TIMEOUT {
+        let status = status();
+        if status & OUTPUT_FULL != 0 {
+            let byte = unsafe { port.read() };
+            if (status & MOUSE_DATA != 0) == (device == Device::Mouse) {
+                return Ok(byte);
+            }
+        }
+        spin_loop();
+    }
+    Err(Error::Timeout)
+}
+
+// For interrupt handlers, the status already says there is something to read
+pub fn read_output() -> u8 {
+    let mut port: Port<u8> = Port::new(DATA_PORT);
+    unsafe { port.read() }
+}
+
+// Returns whether there is a second port for a mouse
+// Only the keyboard interrupt is turned on, the mouse driver turns on its own once the mouse is set up
+pub fn init() -> Result<bool, Error> {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        write_command(DISABLE_KEYBOARD_PORT)?;
+        write_command(DISABLE_MOUSE_PORT)?;
+        flush();
+
+        // Translation stays however the BIOS set it up, pc_keyboard expects scancode set 1
+        let mut config = read_config()? & !(KEYBOARD_IRQ | MOUSE_IRQ);
+        write_config(config)?;
+
+        write_command(SELF_TEST)?;
+        match read_data()? {
+            0x55 => {}
+            result => return Err(Error::SelfTestFailed(result)),
+        }
+        // Some controllers reset themselves during the self test
+        write_config(config)?;
+
+        // The mouse port only exists if its clock can be turned on
+        write_command(ENABLE_MOUSE_PORT)?;
+        let mut has_mouse_port = read_config()? & MOUSE_CLOCK_DISABLED == 0;
+        write_command(DISABLE_MOUSE_PORT)?;
+
+        write_command(TEST_KEYBOARD_PORT)?;
+        match read_data()? {
+            0x00 => {}
+            result => return Err(Error::PortTestFailed(Device::Keyboard, result)),
+        }
+        if has_mouse_port {
+            write_command(TEST_MOUSE_PORT)?;
+            has_mouse_port = read_data()? == 0x00;
+        }
+
+        write_command(ENABLE_KEYBOARD_PORT)?;
+        if has_mouse_port {
+            write_command(ENABLE_MOUSE_PORT)?;
+        }
+        config |= KEYBOARD_IRQ;
+        write_config(config)?;
+
+        Ok(has_mouse_port)
+    })
+}
+
+pub fn enable_mouse_interrupts() -> Result<(), Error> {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let config = read_config()?;
+        write_config(config | MOUSE_IRQ)
+    })
+}
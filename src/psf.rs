@@ -0,0 +1,120 @@
+// PC Screen Font (PSF) bitmap fonts, both version 1 and 2
+// The built-in font is a PSF1 file, 256 glyphs of 8x16 in code page 437 order
+
+pub static DEFAULT_FONT: &[u8] = include_bytes!("fonts/default8x16.psf");
+
+const PSF1_MAGIC: [u8; 2] = [0x36, 0x04];
+const PSF1_MODE_512: u8 = 0x01;
+const PSF2_MAGIC: [u8; 4] = [0x72, 0xb5, 0x4a, 0x86];
+
+#[derive(Debug, Clone, Copy)]
+pub struct Font<'a> {
+    pub width: usize,
+    pub height: usize,
+    pub glyph_count: usize,
+    bytes_per_glyph: usize,
+    glyphs: &'a [u8],
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<usize> {
+    let bytes = data.get(offset..offset + 4)?;
+    Some(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize)
+}
+
+impl<'a> Font<'a> {
+    pub fn parse(data: &'a [u8]) -> Option<Font<'a>> {
+        if data.starts_with(&PSF1_MAGIC) {
+            let mode = *data.get(2)?;
+            let height = *data.get(3)? as usize;
+            let glyph_count = if mode & PSF1_MODE_512 != 0 { 512 } else { 256 };
+            let glyphs = data.get(4..4 + glyph_count * height)?;
+            Some(Font {
+                width: 8,
+                height,
+                glyph_count,
+                bytes_per_glyph: height,
+                glyphs,
+            })
+        } else if data.starts_with(&PSF2_MAGIC) {
+            let header_size = read_u32(data, 8)?;
+            let glyph_count = read_u32(data, 16)?;
+            let bytes_per_glyph = read_u32(data, 20)?;
+            let height = read_u32(data, 24)?;
+            let width = read_u32(data, 28)?;
+            if bytes_per_glyph != height * ((width + 7) / 8) {
+                return None;
+            }
+            let glyphs = data.get(header_size..header_size + glyph_count * bytes_per_glyph)?;
+            Some(Font {
+                width,
+                height,
+                glyph_count,
+                bytes_per_glyph,
+                glyphs,
+            })
+        } else {
+            None
+        }
+    }
+
+    pub fn default() -> Font<'static> {
+        Font::parse(DEFAULT_FONT).unwrap()
+    }
+
+    // Bitmap of one glyph, rows are padded to whole bytes with the leftmost pixel in the high bit
+    pub fn glyph(&self, index: usize) -> &'a [u8] {
+        let index = if index < self.glyph_count { index } else { 0 };
+        let start = index * self.bytes_per_glyph;
+        &self.glyphs[start..start + self.bytes_per_glyph]
+    }
+
+    pub fn bytes_per_row(&self) -> usize {
+        (self.width + 7) / 8
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    // A PSF2 font with two glyphs of 10x3, glyph 1 is all ones
+    fn psf2(bytes_per_glyph: u32) -> Vec<u8> {
+        let mut data = Vec::new();
+        data.extend_from_slice(&PSF2_MAGIC);
+        for &field in &[0, 32, 0, 2, bytes_per_glyph, 3, 10] {
+            data.extend_from_slice(&u32::to_le_bytes(field));
+        }
+        data.extend_from_slice(&[0; 6]);
+        data.extend_from_slice(&[0xff; 6]);
+        data
+    }
+
+    #[test_case]
+    fn built_in_font() {
+        let font = Font::default();
+        assert_eq!((font.width, font.height, font.glyph_count), (8, 16, 256));
+        assert_eq!(font.bytes_per_row(), 1);
+        assert_eq!(font.glyph(b'A' as usize).len(), 16);
+    }
+
+    #[test_case]
+    fn psf2_font() {
+        let data = psf2(6);
+        let font = Font::parse(&data).unwrap();
+        assert_eq!((font.width, font.height, font.glyph_count), (10, 3, 2));
+        assert_eq!(font.bytes_per_row(), 2);
+        assert_eq!(font.glyph(1), [0xff; 6]);
+        // Glyphs that aren't there show the first one
+        assert_eq!(font.glyph(2), [0; 6]);
+    }
+
+    #[test_case]
+    fn broken_fonts() {
+        assert!(Font::parse(&psf2(4)).is_none());
+        let data = psf2(6);
+        assert!(Font::parse(&data[..data.len() - 1]).is_none());
+        assert!(Font::parse(&DEFAULT_FONT[..100]).is_none());
+        assert!(Font::parse(b"not a font").is_none());
+    }
+}
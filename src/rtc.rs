@@ -0,0 +1,379 @@
+// CMOS real-time clock, keeps the date and time while the computer is off
+// The RTC is assumed to be set to UTC
+use crate::error;
+use core::fmt;
+use core::hint::spin_loop;
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+
+const ADDRESS_PORT: u16 = 0x70;
+const DATA_PORT: u16 = 0x71;
+
+// CMOS registers
+const SECOND: u8 = 0x00;
+const MINUTE: u8 = 0x02;
+const HOUR: u8 = 0x04;
+const DAY: u8 = 0x07;
+const MONTH: u8 = 0x08;
+const YEAR: u8 = 0x09;
+// Not standard, but it's where every PC since the AT keeps it
+const CENTURY: u8 = 0x32;
+const STATUS_A: u8 = 0x0A;
+const STATUS_B: u8 = 0x0B;
+
+// Status bits
+const UPDATE_IN_PROGRESS: u8 = 0x80;
+const STOP_UPDATES: u8 = 0x80;
+const BINARY: u8 = 0x04;
+const HOURS_24: u8 = 0x02;
+// Set in the hour register for PM in 12 hour mode
+const PM: u8 = 0x80;
+
+const WEEKDAYS: [&str; 7] = ["Mon", "Tue", "Wed", "Thu", "Fri", "Sat", "Sun"];
+
+// A date and time in UTC
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+fn is_leap_year(year: u16) -> bool {
+    year % 4 == 0 && (year % 100 != 0 || year % 400 == 0)
+}
+
+fn days_in_month(year: u16, month: u8) -> u8 {
+    match month {
+        2 if is_leap_year(year) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+// Days since 1970-01-01, see http://howardhinnant.github.io/date_algorithms.html
+fn days_from_civil(year: u16, month: u8, day: u8) -> u64 {
+    // Years start in March so the leap day is at the end
+    let year = if month <= 2 { year - 1 } else { year } as u64;
+    let month = month as u64;
+    let era = year / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day as u64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+fn civil_from_days(days: u64) -> (u16, u8, u8) {
+    let days = days + 719_468;
+    let era = days / 146_097;
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year as u16, month as u8, day as u8)
+}
+
+impl DateTime {
+    pub fn from_unix(seconds: u64) -> DateTime {
+        let (year, month, day) = civil_from_days(seconds / 86_400);
+        let seconds = seconds % 86_400;
+        DateTime {
+            year,
+            month,
+            day,
+            hour: (seconds / 3600) as u8,
+            minute: (seconds / 60 % 60) as u8,
+            second: (seconds % 60) as u8,
+        }
+    }
+
+    pub fn to_unix(&self) -> u64 {
+        days_from_civil(self.year, self.month, self.day) * 86_400
+            + self.hour as u64 * 3600
+            + self.minute as u64 * 60
+            + self.second as u64
+    }
+
+    pub fn weekday(&self) -> &'static str {
+        // 1970-01-01 was a Thursday
+        let days = days_from_civil(self.year, self.month, self.day);
+        WEEKDAYS[((days + 3) % 7) as usize]
+    }
+
+    // Anything before 1970 can't be a unix time
+    pub fn is_valid(&self) -> bool {
+        (1970..=9999).contains(&self.year)
+            && (1..=12).contains(&self.month)
+            && self.day >= 1
+            && self.day <= days_in_month(self.year, self.month)
+            && self.hour < 24
+            && self.minute < 60
+            && self.second < 60
+    }
+
+    // "YYYY-MM-DD HH:MM:SS", the seconds can be left out
+    pub fn parse(text: &str) -> Option<DateTime> {
+        let mut parts = text.trim().splitn(2, ' ');
+        let mut date = parts.next()?.split('-');
+        let mut time = parts.next()?.trim().split(':');
+
+        let datetime = DateTime {
+            year: date.next()?.parse().ok()?,
+            month: date.next()?.parse().ok()?,
+            day: date.next()?.parse().ok()?,
+            hour: time.next()?.parse().ok()?,
+            minute: time.next()?.parse().ok()?,
+            second: time.next().map_or(Some(0), |second| second.parse().ok())?,
+        };
+
+        if date.next().is_some() || time.next().is_some() || !datetime.is_valid() {
+            return None;
+        }
+        Some(datetime)
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+fn read_register(register: u8) -> u8 {
+    let mut address: Port<u8> = Port::new(ADDRESS_PORT);
+    let mut data: Port<u8> = Port::new(DATA_PORT);
+    unsafe {
+        address.write(register);
+        data.read()
+    }
+}
+
+fn write_register(register: u8, value: u8) {
+    let mut address: Port<u8> = Port::new(ADDRESS_PORT);
+    let mut data: Port<u8> = Port::new(DATA_PORT);
+    unsafe {
+        address.write(register);
+        data.write(value);
+    }
+}
+
+fn from_bcd(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0x0F)
+}
+
+fn to_bcd(value: u8) -> u8 {
+    (value / 10) << 4 | value % 10
+}
+
+// Raw register values, in whatever format the RTC uses
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Registers {
+    second: u8,
+    minute: u8,
+    hour: u8,
+    day: u8,
+    month: u8,
+    year: u8,
+    century: u8,
+}
+
+fn read_registers() -> Registers {
+    while read_register(STATUS_A) & UPDATE_IN_PROGRESS != 0 {
+        spin_loop();
+    }
+
+    Registers {
+        second: read_register(SECOND),
+        minute: read_register(MINUTE),
+        hour: read_register(HOUR),
+        day: read_register(DAY),
+        month: read_register(MONTH),
+        year: read_register(YEAR),
+        century: read_register(CENTURY),
+    }
+}
+
+pub fn read() -> DateTime {
+    let (registers, status) = interrupts::without_interrupts(|| {
+        // An update can still start while we read, so read until we get the same values twice
+        let mut registers = read_registers();
+        loop {
+            let again = read_registers();
+            if again == registers {
+                break;
+            }
+            registers = again;
+        }
+        (registers, read_register(STATUS_B))
+    });
+
+    let decode = |value: u8| {
+        if status & BINARY != 0 {
+            value
+        } else {
+            from_bcd(value)
+        }
+    };
+
+    let pm = registers.hour & PM != 0;
+    let mut hour = decode(registers.hour & !PM);
+    if status & HOURS_24 == 0 {
+        // 12 AM is midnight
+        hour = hour % 12 + if pm { 12 } else { 0 };
+    }
+
+    // Not every RTC has a century register
+    let century = match decode(registers.century) {
+        19..=99 => decode(registers.century) as u16,
+        _ => 20,
+    };
+
+    DateTime {
+        year: century * 100 + decode(registers.year) as u16,
+        month: decode(registers.month),
+        day: decode(registers.day),
+        hour,
+        minute: decode(registers.minute),
+        second: decode(registers.second),
+    }
+}
+
+pub fn write(datetime: &DateTime) {
+    interrupts::without_interrupts(|| {
+        let status = read_register(STATUS_B);
+        let encode = |value: u8| {
+            if status & BINARY != 0 {
+                value
+            } else {
+                to_bcd(value)
+            }
+        };
+
+        let has_century = matches!(from_bcd(read_register(CENTURY)), 19..=99)
+            || matches!(read_register(CENTURY), 19..=99);
+        let hour = if status & HOURS_24 != 0 {
+            encode(datetime.hour)
+        } else {
+            let pm = if datetime.hour >= 12 { PM } else { 0 };
+            match datetime.hour % 12 {
+                0 => encode(12) | pm,
+                hour => encode(hour) | pm,
+            }
+        };
+
+        // Stop the clock from updating while we change it
+        write_register(STATUS_B, status | STOP_UPDATES);
+        write_register(SECOND, encode(datetime.second));
+        write_register(MINUTE, encode(datetime.minute));
+        write_register(HOUR, hour);
+        write_register(DAY, encode(datetime.day));
+        write_register(MONTH, encode(datetime.month));
+        write_register(YEAR, encode((datetime.year % 100) as u8));
+        // Without a century register 0x32 is just CMOS memory that something else might use
+        if has_century {
+            write_register(CENTURY, encode((datetime.year / 100) as u8));
+        }
+        write_register(STATUS_B, status);
+    });
+}
+
+// Must be called after the clock is initialized
+// A garbage date would underflow in to_unix, so the clock starts at the epoch instead
+pub fn init() -> DateTime {
+    let now = read();
+    if !now.is_valid() {
+        error!("RTC has an invalid date ({}), starting the clock at 0", now);
+        crate::clock::set_realtime(0);
+        return DateTime::from_unix(0);
+    }
+    crate::clock::set_realtime(now.to_unix());
+    now
+}
+
+pub fn now() -> DateTime {
+    DateTime::from_unix(crate::clock::realtime())
+}
+
+// Changes the RTC and the kernel's clock
+pub fn set(datetime: &DateTime) {
+    write(datetime);
+    crate::clock::set_realtime(datetime.to_unix());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn datetime(year: u16, month: u8, day: u8, hour: u8, minute: u8, second: u8) -> DateTime {
+        DateTime {
+            year,
+            month,
+            day,
+            hour,
+            minute,
+            second,
+        }
+    }
+
+    #[test_case]
+    fn unix_epoch() {
+        let epoch = DateTime::from_unix(0);
+        assert_eq!(epoch, datetime(1970, 1, 1, 0, 0, 0));
+        assert_eq!(epoch.weekday(), "Thu");
+        assert_eq!(epoch.to_unix(), 0);
+    }
+
+    #[test_case]
+    fn leap_day() {
+        let leap_day = datetime(2000, 2, 29, 0, 0, 0);
+        assert_eq!(leap_day.to_unix(), 951_782_400);
+        assert_eq!(DateTime::from_unix(951_782_400), leap_day);
+        assert_eq!(leap_day.weekday(), "Tue");
+    }
+
+    #[test_case]
+    fn round_trip() {
+        for &seconds in &[1, 86_399, 86_400, 2_147_483_648, 4_102_444_799] {
+            assert_eq!(DateTime::from_unix(seconds).to_unix(), seconds);
+        }
+    }
+
+    #[test_case]
+    fn parse() {
+        assert_eq!(
+            DateTime::parse("2038-01-19 03:14:08"),
+            Some(datetime(2038, 1, 19, 3, 14, 8))
+        );
+        assert_eq!(
+            DateTime::parse(" 2024-02-29 12:34 "),
+            Some(datetime(2024, 2, 29, 12, 34, 0))
+        );
+        assert_eq!(DateTime::parse("2023-02-29 12:34"), None);
+        assert_eq!(DateTime::parse("1969-12-31 23:59:59"), None);
+        assert_eq!(DateTime::parse("2024-01-01 24:00"), None);
+        assert_eq!(DateTime::parse("2024-01-01"), None);
+        assert_eq!(DateTime::parse("2024-01-01 10:00:00:00"), None);
+    }
+
+    #[test_case]
+    fn display() {
+        let text = alloc::format!("{}", datetime(2024, 3, 5, 7, 8, 9));
+        assert_eq!(text, "2024-03-05 07:08:09");
+    }
+}
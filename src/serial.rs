@@ -0,0 +1,169 @@
+// 16550 UART driver for the COM1 serial port
+use crate::ansi;
+use crate::vga_buffer::Color;
+use bit_field::BitField;
+use core::fmt;
+use lazy_static::lazy_static;
+use spin::Mutex;
+use x86_64::instructions::port::{Port, PortReadOnly};
+
+pub const COM1: u16 = 0x3F8;
+pub const COM1_IRQ: u8 = 4;
+
+// 115200 / 1 = 115200 baud
+const DIVISOR: u16 = 1;
+
+#[allow(dead_code)]
+#[repr(usize)]
+enum LineStatus {
+    DataReady = 0,
+    OverrunError,
+    ParityError,
+    FramingError,
+    Break,
+    TransmitterEmpty,
+    TransmitterIdle,
+    FifoError,
+}
+
+pub struct SerialPort {
+    data: Port<u8>,
+    interrupt_enable: Port<u8>,
+    fifo_control: Port<u8>,
+    line_control: Port<u8>,
+    modem_control: Port<u8>,
+    line_status: PortReadOnly<u8>,
+}
+
+impl SerialPort {
+    pub fn new(base: u16) -> SerialPort {
+        SerialPort {
+            data: Port::new(base),
+            interrupt_enable: Port::new(base + 1),
+            fifo_control: Port::new(base + 2),
+            line_control: Port::new(base + 3),
+            modem_control: Port::new(base + 4),
+            line_status: PortReadOnly::new(base + 5),
+        }
+    }
+
+    pub fn init(&mut self) {
+        let [divisor_low, divisor_high] = DIVISOR.to_le_bytes();
+        unsafe {
+            self.interrupt_enable.write(0x00);
+
+            // The divisor is written to the data and interrupt enable ports while DLAB is set
+            self.line_control.write(0x80);
+            self.data.write(divisor_low);
+            self.interrupt_enable.write(divisor_high);
+
+            // 8 data bits, no parity, one stop bit
+            self.line_control.write(0x03);
+            // Enable and clear the FIFOs, interrupt when 14 bytes are waiting
+            self.fifo_control.write(0xC7);
+            // DTR, RTS and OUT2, which has to be set for interrupts to reach the PIC
+            self.modem_control.write(0x0B);
+            // Interrupt when data is received
+            self.interrupt_enable.write(0x01);
+        }
+    }
+
+    fn status(&mut self) -> u8 {
+        unsafe { self.line_status.read() }
+    }
+
+    pub fn send(&mut self, byte: u8) {
+        while !self.status().get_bit(LineStatus::TransmitterEmpty as usize) {
+            core::hint::spin_loop();
+        }
+        unsafe { self.data.write(byte) }
+    }
+
+    pub fn receive(&mut self) -> Option<u8> {
+        if self.status().get_bit(LineStatus::DataReady as usize) {
+            Some(unsafe { self.data.read() })
+        } else {
+            None
+        }
+    }
+}
+
+impl fmt::Write for SerialPort {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for byte in s.bytes() {
+            // Terminals need a carriage return to go back to the start of the line
+            if byte == b'\n' {
+                self.send(b'\r');
+            }
+            self.send(byte);
+        }
+        Ok(())
+    }
+}
+
+lazy_static! {
+    pub static ref SERIAL1: Mutex<SerialPort> = Mutex::new(SerialPort::new(COM1));
+}
+
+pub fn init() {
+    SERIAL1.lock().init();
+    crate::interrupts::register(COM1_IRQ, "serial", interrupt_handler).expect("COM1 IRQ is taken");
+}
+
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+    use core::fmt::Write;
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        SERIAL1.lock().write_fmt(args).unwrap();
+    });
+}
+
+// Same as the console's change_color, as an ANSI escape sequence
+pub fn change_color(foreground: Color, background: Color) {
+    let (foreground, background) = (foreground as u8, background as u8);
+    let foreground_base = if foreground & 8 != 0 { 90 } else { 30 };
+    let background_base = if background & 8 != 0 { 100 } else { 40 };
+    _print(format_args!(
+        "\x1b[{};{}m",
+        foreground_base + ansi::ansi_color(foreground & 7),
+        background_base + ansi::ansi_color(background & 7)
+    ));
+}
+
+// Terminals move left on backspace without erasing anything
+pub fn move_back(characters: usize) {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut serial = SERIAL1.lock();
+        for _ in 0..characters {
+            serial.send(0x08);
+        }
+    });
+}
+
+fn interrupt_handler() -> bool {
+    // Don't hold the lock while the shell runs, it prints to the serial port too
+    let mut received = [0u8; 16];
+    let mut count = 0;
+    {
+        let mut serial = SERIAL1.lock();
+        while count < received.len() {
+            match serial.receive() {
+                Some(byte) => {
+                    received[count] = byte;
+                    count += 1;
+                }
+                None => break,
+            }
+        }
+    }
+
+    for &byte in &received[..count] {
+        crate::shell::handle_serial(byte);
+    }
+    // COM1 has the line to itself
+    true
+}
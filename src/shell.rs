@@ -1,31 +1,416 @@
+use crate::ansi::{Action, Parser};
+use crate::console::{self, change_color};
 use crate::print;
 use crate::println;
-use crate::vga_buffer::ScreenChar;
-use crate::vga_buffer::{change_color, Color};
+use crate::serial;
+use crate::vga_buffer::{active, Color, CONSOLES};
+use alloc::collections::VecDeque;
+use alloc::format;
 use alloc::vec;
 use alloc::{string::String, vec::Vec};
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
+use lazy_static::lazy_static;
+use pc_keyboard::{DecodedKey, KeyCode};
+use spin::Mutex;
 
-pub fn evaluate(command: &str) {
-    if let Some(stripped) = command.strip_prefix(">>> ") {
-        let res = stripped.trim();
-        if res != "" {
-            println!();
-            let parts: Vec<&str> = res.split(" ").collect();
-            let selected = match parts[0] {
-                "help" => help,
-                "info" => info,
-                "echo" => echo,
-                "shutdown" => shutdown,
-                "clear" => clear,
-                "uptime" => uptime,
-                _ => default,
+pub const PROMPT: &str = ">>> ";
+
+// Where a shell's line editing shows up, the keyboard and the serial port don't see each other's
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Echo {
+    // One of the VGA consoles
+    Screen(usize),
+    Serial,
+}
+
+impl Echo {
+    fn print(self, args: fmt::Arguments) {
+        match self {
+            Echo::Screen(console) => console::print_on(console, args),
+            Echo::Serial => serial::_print(args),
+        }
+    }
+
+    fn move_back(self, characters: usize) {
+        match self {
+            Echo::Screen(console) => console::move_back(console, characters),
+            Echo::Serial => serial::move_back(characters),
+        }
+    }
+
+    // Prints text and puts the cursor back where it was
+    // The console keeps the saved position right when the text wraps on the last row and scrolls,
+    // terminals on the serial port don't, backspaces at least stay on the same row there
+    fn print_after_cursor(self, text: &str) {
+        match self {
+            Echo::Screen(console) => {
+                console::print_on(console, format_args!("\x1b[s{}\x1b[u", text))
+            }
+            Echo::Serial => {
+                serial::_print(format_args!("{}", text));
+                serial::move_back(text.chars().count());
+            }
+        }
+    }
+
+    // Command output goes everywhere, so the other side gets to see the command too
+    fn print_elsewhere(self, args: fmt::Arguments) {
+        match self {
+            Echo::Screen(_) => serial::_print(args),
+            Echo::Serial => console::print_screen(args),
+        }
+    }
+}
+
+// The line currently being typed at the prompt
+struct Input {
+    line: Vec<char>,
+    // Index into line, not a screen position
+    cursor: usize,
+    echo: Echo,
+    // A command that was entered and is waiting for the main loop to run it
+    pending: Option<String>,
+    // Set from the time a command is entered until it's done
+    running: bool,
+}
+
+impl Input {
+    // Print everything after the cursor and move back to where we were
+    fn redraw_tail(&self, erase: usize) {
+        let mut tail: String = self.line[self.cursor..].iter().collect();
+        for _ in 0..erase {
+            tail.push(' ');
+        }
+        self.echo.print_after_cursor(&tail);
+    }
+
+    fn insert(&mut self, character: char) {
+        self.line.insert(self.cursor, character);
+        self.echo.print(format_args!("{}", character));
+        self.cursor += 1;
+        self.redraw_tail(0);
+    }
+
+    fn backspace(&mut self) {
+        if self.cursor > 0 {
+            self.cursor -= 1;
+            self.line.remove(self.cursor);
+            self.echo.move_back(1);
+            self.redraw_tail(1);
+        }
+    }
+
+    fn delete(&mut self) {
+        if self.cursor < self.line.len() {
+            self.line.remove(self.cursor);
+            self.redraw_tail(1);
+        }
+    }
+
+    fn left(&mut self) {
+        if self.cursor > 0 {
+            self.cursor -= 1;
+            self.echo.move_back(1);
+        }
+    }
+
+    fn right(&mut self) {
+        if self.cursor < self.line.len() {
+            self.echo.print(format_args!("{}", self.line[self.cursor]));
+            self.cursor += 1;
+        }
+    }
+
+    // Prints the prompt and the whole line, for when the screen was cleared
+    fn redraw(&self) {
+        let line: String = self.line.iter().collect();
+        self.echo.print(format_args!("{}{}", PROMPT, line));
+        self.echo.move_back(self.line.len() - self.cursor);
+    }
+
+    fn take(&mut self) -> String {
+        self.cursor = 0;
+        self.line.drain(..).collect()
+    }
+}
+
+impl Input {
+    fn new(echo: Echo) -> Input {
+        Input {
+            line: Vec::new(),
+            cursor: 0,
+            echo,
+            pending: None,
+            running: false,
+        }
+    }
+}
+
+lazy_static! {
+    // Every console runs its own shell
+    static ref INPUTS: [Mutex<Input>; CONSOLES] = [
+        Mutex::new(Input::new(Echo::Screen(0))),
+        Mutex::new(Input::new(Echo::Screen(1))),
+        Mutex::new(Input::new(Echo::Screen(2))),
+        Mutex::new(Input::new(Echo::Screen(3))),
+    ];
+    // The serial port gets a shell of its own
+    static ref SERIAL_INPUT: Mutex<Input> = Mutex::new(Input::new(Echo::Serial));
+    // Text pasted with the mouse and the console it goes to, typed from the main loop
+    static ref PASTED: Mutex<VecDeque<(usize, char)>> = Mutex::new(VecDeque::new());
+}
+
+// Set by Ctrl+C, long running commands should check it and stop
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+pub fn handle_key(key: DecodedKey) {
+    edit(&INPUTS[active()], key);
+}
+
+// Called from the mouse interrupt handler, so nothing is typed here
+pub fn paste(text: &str) {
+    let console = active();
+    PASTED
+        .lock()
+        .extend(text.chars().map(|character| (console, character)));
+}
+
+// Types pasted text until it runs out or it reaches a shell that is running a command
+fn type_pasted() {
+    use x86_64::instructions::interrupts;
+
+    loop {
+        // The keyboard interrupt edits the same inputs
+        let typed = interrupts::without_interrupts(|| {
+            let (console, character) = match PASTED.lock().front() {
+                Some(&next) => next,
+                None => return false,
             };
-            selected(&parts[..]);
-            print!(">>> ");
+            if INPUTS[console].lock().running {
+                return false;
+            }
+            PASTED.lock().pop_front();
+            edit(&INPUTS[console], DecodedKey::Unicode(character));
+            true
+        });
+        if !typed {
+            return;
         }
     }
 }
 
+// Puts characters back together from UTF-8, terminals send anything past ASCII that way
+struct Utf8 {
+    bytes: [u8; 4],
+    len: usize,
+    // How many bytes the character has, 0 between characters
+    expected: usize,
+}
+
+impl Utf8 {
+    const fn new() -> Utf8 {
+        Utf8 {
+            bytes: [0; 4],
+            len: 0,
+            expected: 0,
+        }
+    }
+
+    // None until a character is complete, broken sequences are dropped
+    fn push(&mut self, byte: u8) -> Option<char> {
+        if byte & 0xC0 == 0x80 && self.expected != 0 {
+            self.bytes[self.len] = byte;
+            self.len += 1;
+            if self.len < self.expected {
+                return None;
+            }
+            self.expected = 0;
+            // Catches overlong encodings and surrogates
+            let text = core::str::from_utf8(&self.bytes[..self.len]).ok()?;
+            return text.chars().next();
+        }
+
+        // Anything that isn't a continuation ends the character that was cut short
+        self.expected = 0;
+        self.expected = match byte {
+            0x00..=0x7f => return Some(byte as char),
+            0xc2..=0xdf => 2,
+            0xe0..=0xef => 3,
+            0xf0..=0xf4 => 4,
+            _ => return None,
+        };
+        self.bytes[0] = byte;
+        self.len = 1;
+        None
+    }
+}
+
+// Turns what a terminal on the other end of the serial port sends into keys
+pub fn handle_serial(byte: u8) {
+    static PARSER: Mutex<Parser> = Mutex::new(Parser::new());
+    static UTF8: Mutex<Utf8> = Mutex::new(Utf8::new());
+    // Enter can be sent as \r, \n or both
+    static CARRIAGE_RETURN: AtomicBool = AtomicBool::new(false);
+
+    let action = PARSER.lock().advance(byte);
+    let after_return =
+        CARRIAGE_RETURN.swap(action == Some(Action::Print(b'\r')), Ordering::Relaxed);
+    let key = match action {
+        Some(Action::Print(b'\r')) => DecodedKey::Unicode('\n'),
+        Some(Action::Print(b'\n')) if after_return => return,
+        Some(Action::Print(b'\n')) => DecodedKey::Unicode('\n'),
+        // Most terminals send DEL for backspace
+        Some(Action::Print(0x08)) | Some(Action::Print(0x7f)) => DecodedKey::Unicode('\u{8}'),
+        Some(Action::Print(b'\t')) => DecodedKey::Unicode('\t'),
+        // Ctrl+C and friends
+        Some(Action::Print(byte @ 0x00..=0x1f)) => DecodedKey::Unicode(byte as char),
+        Some(Action::Print(byte)) => match UTF8.lock().push(byte) {
+            Some(character) => DecodedKey::Unicode(character),
+            None => return,
+        },
+        Some(Action::Csi(csi)) => match (csi.action, csi.param(0, 0)) {
+            (b'C', _) => DecodedKey::RawKey(KeyCode::ArrowRight),
+            (b'D', _) => DecodedKey::RawKey(KeyCode::ArrowLeft),
+            (b'~', 3) => DecodedKey::Unicode('\u{7f}'),
+            _ => return,
+        },
+        _ => return,
+    };
+    edit(&SERIAL_INPUT, key);
+}
+
+fn edit(input: &Mutex<Input>, key: DecodedKey) {
+    let mut input = input.lock();
+    // Anything typed while the shell's command runs is dropped, except for Ctrl+C
+    if input.running {
+        if key == DecodedKey::Unicode('\u{3}') {
+            input.echo.print(format_args!("^C\n"));
+            // A command still waiting for another shell's to finish just doesn't run
+            if input.pending.take().is_some() {
+                input.running = false;
+                input.echo.print(format_args!("{}", PROMPT));
+            } else {
+                INTERRUPTED.store(true, Ordering::Relaxed);
+            }
+        }
+        return;
+    }
+
+    match key {
+        DecodedKey::Unicode('\n') => {
+            let line = input.take();
+            if line.trim().is_empty() {
+                return;
+            }
+            input.echo.print_elsewhere(format_args!("{}", line));
+            // Commands can take a while, the main loop runs them outside of the interrupt handler
+            input.running = true;
+            input.pending = Some(line);
+        }
+        // Ctrl+C throws away the line
+        DecodedKey::Unicode('\u{3}') => {
+            input.take();
+            input.echo.print(format_args!("^C\n{}", PROMPT));
+        }
+        // Ctrl+D on an empty line ends the session and starts a fresh one
+        DecodedKey::Unicode('\u{4}') if input.line.is_empty() => {
+            input.echo.print(format_args!("\x1b[2J\x1b[H{}", PROMPT));
+        }
+        DecodedKey::Unicode('\u{4}') => input.delete(),
+        // Ctrl+L clears the screen but keeps the line
+        DecodedKey::Unicode('\u{c}') => {
+            input.echo.print(format_args!("\x1b[2J\x1b[H"));
+            input.redraw();
+        }
+        DecodedKey::Unicode('\u{8}') => input.backspace(),
+        DecodedKey::Unicode('\u{7f}') => input.delete(),
+        DecodedKey::Unicode('\t') => {
+            for _ in 0..4 {
+                input.insert(' ');
+            }
+        }
+        DecodedKey::Unicode(character) if character.is_control() => {}
+        DecodedKey::Unicode(character) => input.insert(character),
+        DecodedKey::RawKey(KeyCode::ArrowLeft) => input.left(),
+        DecodedKey::RawKey(KeyCode::ArrowRight) => input.right(),
+        DecodedKey::RawKey(_) => {} // Ignore all other special keys
+    }
+}
+
+// Called from the main loop, keyboard interrupts keep coming in while the command runs
+pub fn run_pending() {
+    use x86_64::instructions::interrupts;
+
+    loop {
+        type_pasted();
+        // Only one command runs at a time, the other shells' commands wait their turn
+        let next = INPUTS
+            .iter()
+            .chain(core::iter::once(&*SERIAL_INPUT))
+            .find_map(|input| {
+                interrupts::without_interrupts(|| {
+                    let mut locked = input.lock();
+                    let echo = locked.echo;
+                    locked.pending.take().map(|line| (input, echo, line))
+                })
+            });
+        let (input, echo, line) = match next {
+            Some(next) => next,
+            None => return,
+        };
+
+        // The output stays on the console the command was typed on, even after switching away
+        console::set_output(match echo {
+            Echo::Screen(console) => Some(console),
+            Echo::Serial => None,
+        });
+        evaluate(&line);
+        console::set_output(None);
+        INTERRUPTED.store(false, Ordering::Relaxed);
+        interrupts::without_interrupts(|| input.lock().running = false);
+    }
+}
+
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::Relaxed)
+}
+
+// Like timer::sleep, but returns false if Ctrl+C was pressed in the meantime
+fn pause(duration: Duration) -> bool {
+    crate::timer::sleep_unless(duration, interrupted)
+}
+
+pub fn evaluate(command: &str) {
+    let res = command.trim();
+    if res != "" {
+        println!();
+        let parts: Vec<&str> = res.split(" ").collect();
+        let selected = match parts[0] {
+            "help" => help,
+            "info" => info,
+            "echo" => echo,
+            "shutdown" => shutdown,
+            "clear" => clear,
+            "uptime" => uptime,
+            "gfxdemo" => gfxdemo,
+            "fbcon" => fbcon,
+            "setfont" => setfont,
+            "dmesg" => dmesg,
+            "log" => log,
+            "keymap" => keymap,
+            "date" => date,
+            "timers" => timers,
+            "irqs" => irqs,
+            "meminfo" => meminfo,
+            _ => default,
+        };
+        selected(&parts[..]);
+        print!("{}", PROMPT);
+    }
+}
+
 fn compute_edit_distance(a: &str, b: &str) -> usize {
     let len_a = a.chars().count();
     let len_b = b.chars().count();
@@ -69,7 +454,10 @@ fn compute_edit_distance(a: &str, b: &str) -> usize {
 fn default(arguments: &[&str]) {
     let mut distances: Vec<(&str, usize)> = Vec::new();
     let curr = arguments[0];
-    for &command in &["help", "info", "echo", "shutdown", "clear", "uptime"] {
+    for &command in &[
+        "help", "info", "echo", "shutdown", "clear", "uptime", "gfxdemo", "fbcon", "setfont",
+        "dmesg", "log", "keymap", "date", "timers", "irqs", "meminfo",
+    ] {
         let distance = compute_edit_distance(curr, command);
         distances.push((command, distance));
     }
@@ -83,11 +471,26 @@ fn help(_arguments: &[&str]) {
     change_color(Color::LightBlue, Color::Black);
     print!("KarxShell help menu\n\n");
     println!("[clear] Clears the screen");
+    println!("[date] Shows the date and time in UTC");
+    println!("[date -s <YYYY-MM-DD HH:MM[:SS]>] Sets the date and time");
+    println!("[dmesg [-c] [-T]] Shows the kernel log, -c clears it, -T shows dates");
     println!("[echo <arguments>] Echoes whatever arguments you pass in");
+    println!("[fbcon <on|off>] Switches the console to the framebuffer and back");
+    println!("[gfxdemo] Shows off the VGA graphics modes");
     println!("[help] This message");
     println!("[info] Info about KarxOS");
+    println!("[irqs] Shows the IRQ handlers and how often each IRQ fired");
+    println!("[keymap [layout]] Lists the keyboard layouts or switches to one until reboot");
+    println!("[log level <level> [module]] Sets which messages get logged");
+    println!("[log <screen|serial> <on|off>] Chooses where log messages are shown");
+    println!("[meminfo] Shows how much physical memory and heap is in use");
+    println!("[setfont <bios|fixed>] Switches between the BIOS font and the built-in one");
     println!("[shutdown] Shuts off the system (QEMU only)");
+    println!("[timers] Lists the kernel timers that are waiting to fire");
     println!("[uptime] Get the system uptime");
+    println!();
+    println!("Ctrl+C stops a command, Ctrl+L clears the screen, Ctrl+D starts over");
+    println!("Drag with the mouse to copy text, middle click pastes it");
     change_color(Color::White, Color::Black);
 }
 
@@ -100,7 +503,10 @@ fn info(_arguments: &[&str]) {
     change_color(Color::Red, Color::Black);
     println!("Rust language.");
     change_color(Color::White, Color::Black);
-    println!("If you're having input problems, switch to a US keyboard layout.");
+    println!(
+        "Keyboard layout: {} (change it with keymap)",
+        crate::keymap::layout().description
+    );
 }
 
 fn echo(arguments: &[&str]) {
@@ -127,25 +533,264 @@ fn shutdown(_arguments: &[&str]) {
 }
 
 fn clear(_arguments: &[&str]) {
-    let mut writer = crate::vga_buffer::WRITER.lock();
-
-    for row in 0..crate::vga_buffer::BUFFER_HEIGHT {
-        for col in 0..crate::vga_buffer::BUFFER_WIDTH {
-            let blank = ScreenChar {
-                ascii_character: b' ',
-                color_code: crate::vga_buffer::ColorCode::new(
-                    crate::vga_buffer::Color::White,
-                    crate::vga_buffer::Color::Black,
-                ),
-            };
+    // Erase the screen and move the cursor to the top left
+    print!("\x1b[2J\x1b[H");
+}
+
+fn uptime(_arguments: &[&str]) {
+    use crate::clock::{source, uptime, Seconds};
+
+    println!("Uptime: {} seconds", Seconds(uptime()));
+    println!("Clock source: {}", source());
+}
+
+fn gfxdemo(_arguments: &[&str]) {
+    use crate::graphics::{Mode, GRAPHICS};
+
+    if crate::console::framebuffer_enabled() {
+        println!("Error: turn off the framebuffer console first (fbcon off)");
+        return;
+    }
 
-            writer.buffer.chars[row][col].write(blank);
+    let mut graphics = GRAPHICS.lock();
+
+    // Every color in the palette, with a few shapes on top
+    graphics.set_mode(Mode::Graphics320x200);
+    for color in 0..256 {
+        graphics.draw_line(32 + color, 0, 32 + color, 99, color as u8);
+    }
+    graphics.fill_rect(20, 120, 80, 60, 4);
+    graphics.draw_rect(120, 120, 80, 60, 14);
+    graphics.draw_line(220, 120, 300, 180, 10);
+    graphics.draw_line(220, 180, 300, 120, 11);
+
+    // A checkerboard sprite in a custom orange
+    graphics.set_palette(255, 63, 32, 0);
+    let mut sprite = [0u8; 16 * 16];
+    for (i, pixel) in sprite.iter_mut().enumerate() {
+        if (i / 16 / 4 + i % 16 / 4) % 2 == 0 {
+            *pixel = 255;
+        }
+    }
+    graphics.blit(152, 184, 16, 16, &sprite);
+
+    // All 16 colors as bars, with lines fanning out from the corner
+    if pause(Duration::from_secs(3)) {
+        graphics.set_mode(Mode::Graphics640x480);
+        for color in 0..16 {
+            graphics.fill_rect(color * 40, 0, 40, 240, color as u8);
+        }
+        for i in 0..16 {
+            graphics.draw_line(0, 479, 639, 240 + i * 15, i as u8);
         }
+        graphics.draw_rect(0, 0, 640, 480, 15);
+        pause(Duration::from_secs(3));
     }
+
+    graphics.set_mode(Mode::Text);
+    println!("Back in text mode");
 }
 
-fn uptime(_arguments: &[&str]) {
-    use crate::clock::uptime;
+fn fbcon(arguments: &[&str]) {
+    use crate::console::{disable_framebuffer, enable_framebuffer, size};
+
+    match arguments.get(1) {
+        Some(&"on") => {
+            if enable_framebuffer() {
+                let (columns, rows) = size();
+                println!("Framebuffer console is {}x{}", columns, rows);
+            } else {
+                println!("Error: no framebuffer available");
+            }
+        }
+        Some(&"off") => disable_framebuffer(),
+        _ => println!("Usage: fbcon <on|off>"),
+    }
+}
+
+fn setfont(arguments: &[&str]) {
+    use crate::graphics::{Mode, GRAPHICS};
+    use crate::psf::DEFAULT_FONT;
+    use crate::vga_buffer::{load_psf, restore_bios_font};
+
+    if crate::console::framebuffer_enabled() || GRAPHICS.lock().mode() != Mode::Text {
+        println!("Error: fonts can only be changed in VGA text mode");
+        return;
+    }
+
+    match arguments.get(1) {
+        Some(&"bios") => restore_bios_font(),
+        Some(&"fixed") => {
+            if let Err(error) = load_psf(DEFAULT_FONT) {
+                println!("Error: {:?}", error);
+            }
+        }
+        Some(name) => println!("Error: unknown font {}, it's either bios or fixed", name),
+        None => println!("Usage: setfont <bios|fixed>"),
+    }
+}
+
+fn date(arguments: &[&str]) {
+    use crate::rtc::{self, DateTime};
+
+    match arguments.get(1) {
+        None => {
+            let now = rtc::now();
+            println!("{} {} UTC", now.weekday(), now);
+        }
+        Some(&"-s") => match DateTime::parse(&arguments[2..].join(" ")) {
+            Some(datetime) => rtc::set(&datetime),
+            None => println!("Usage: date -s <YYYY-MM-DD HH:MM[:SS]>"),
+        },
+        Some(_) => println!("Usage: date [-s <YYYY-MM-DD HH:MM[:SS]>]"),
+    }
+}
+
+fn irqs(_arguments: &[&str]) {
+    use crate::interrupts::irq_stats;
+
+    println!(
+        "{:>3} {:>10} {:>9} {:>8}  HANDLERS",
+        "IRQ", "COUNT", "UNHANDLED", "SPURIOUS"
+    );
+    for stats in irq_stats().iter() {
+        let handlers: Vec<&str> = stats.handlers.iter().flatten().copied().collect();
+        if stats.count == 0 && stats.spurious == 0 && handlers.is_empty() {
+            continue;
+        }
+        println!(
+            "{:>3} {:>10} {:>9} {:>8}  {}",
+            stats.irq,
+            stats.count,
+            stats.unhandled,
+            stats.spurious,
+            handlers.join(", ")
+        );
+    }
+}
+
+fn meminfo(_arguments: &[&str]) {
+    use crate::{allocator, frames};
+
+    // Frames are 4 KiB
+    let memory = frames::stats();
+    println!(
+        "Memory: {} KiB used, {} KiB free, {} KiB total",
+        memory.used() * 4,
+        memory.free * 4,
+        memory.usable * 4
+    );
+
+    let heap = allocator::stats();
+    println!(
+        "Heap:   {} KiB used, {} KiB free, {} KiB mapped (up to {} KiB)",
+        heap.used / 1024,
+        (heap.size - heap.used) / 1024,
+        heap.size / 1024,
+        allocator::HEAP_MAX_SIZE / 1024
+    );
+
+    println!();
+    println!("{:>5} {:>7} {:>7}", "SLAB", "BLOCKS", "USED");
+    for slab in heap.slabs.iter() {
+        println!("{:>5} {:>7} {:>7}", slab.size, slab.blocks, slab.used);
+    }
+}
+
+fn timers(_arguments: &[&str]) {
+    use crate::clock::Seconds;
+    use crate::timer;
 
-    println!("Uptime: {:.2} seconds", uptime());
+    let timers = timer::timers();
+    if timers.is_empty() {
+        println!("No timers");
+        return;
+    }
+    println!(
+        "{:>5}  {:<16} {:>10} {:>10} {:>6}",
+        "ID", "NAME", "IN", "EVERY", "FIRED"
+    );
+    for timer in timers {
+        let period = match timer.period {
+            Some(period) => format!("{}s", Seconds(period)),
+            None => String::from("-"),
+        };
+        println!(
+            "{:>5}  {:<16} {:>9}s {:>10} {:>6}",
+            timer.id.as_u64(),
+            timer.name,
+            Seconds(timer.remaining),
+            period,
+            timer.fired
+        );
+    }
+}
+
+fn dmesg(arguments: &[&str]) {
+    use crate::log;
+
+    if arguments.contains(&"-T") {
+        log::for_each_entry(|entry| println!("{}", log::WallClock(entry)));
+    } else {
+        log::for_each_entry(|entry| println!("{}", entry));
+    }
+    if arguments.contains(&"-c") {
+        log::clear();
+    }
+}
+
+fn log(arguments: &[&str]) {
+    use crate::log::{self, Level, Sink};
+
+    match arguments[1..] {
+        [] => {
+            let settings = log::settings();
+            println!("Level: {}", settings.level.name());
+            for (module, level) in &settings.filters {
+                println!("  {}: {}", module, level.name());
+            }
+            let state = |enabled| if enabled { "on" } else { "off" };
+            println!("Screen: {}", state(settings.screen));
+            println!("Serial: {}", state(settings.serial));
+        }
+        ["level", level] | ["level", level, _] => match Level::from_name(level) {
+            Some(level) => log::set_level(arguments.get(3).copied(), level),
+            None => println!("Error: levels are error, warn, info, debug and trace"),
+        },
+        [sink @ "screen", state] | [sink @ "serial", state] => {
+            let sink = if sink == "screen" {
+                Sink::Screen
+            } else {
+                Sink::Serial
+            };
+            match state {
+                "on" => log::set_sink(sink, true),
+                "off" => log::set_sink(sink, false),
+                _ => println!("Usage: log <screen|serial> <on|off>"),
+            }
+        }
+        _ => {
+            println!("Usage: log level <level> [module]");
+            println!("       log <screen|serial> <on|off>");
+        }
+    }
+}
+
+fn keymap(arguments: &[&str]) {
+    use crate::keymap::{layout, set_layout, LAYOUTS};
+
+    match arguments.get(1) {
+        Some(name) => {
+            if !set_layout(name) {
+                println!("Error: unknown layout {}, see keymap for the list", name);
+            }
+        }
+        None => {
+            let current = layout().name;
+            for layout in LAYOUTS.iter() {
+                let marker = if layout.name == current { '*' } else { ' ' };
+                println!("{} {:8} {}", marker, layout.name, layout.description);
+            }
+        }
+    }
 }
@@ -0,0 +1,114 @@
+// Kernel stacks, each one has an unmapped guard page below it
+// Running off the end faults on the guard page instead of overwriting whatever is there
+use crate::{frames, memory};
+use alloc::vec::Vec;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::structures::paging::{
+    mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB,
+};
+use x86_64::VirtAddr;
+
+// Where the stacks get mapped, one after the other
+pub const STACKS_START: u64 = 0x_9999_9999_0000;
+
+// In pages, not counting the guard page
+pub const KERNEL_STACK_PAGES: u64 = 32;
+pub const DOUBLE_FAULT_STACK_PAGES: u64 = 5;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Stack {
+    pub name: &'static str,
+    // The unmapped page right below the stack
+    pub guard: VirtAddr,
+    // The stack grows down from here
+    pub top: VirtAddr,
+}
+
+impl Stack {
+    pub fn bottom(&self) -> VirtAddr {
+        self.guard + 4096u64
+    }
+}
+
+// Where the next stack's guard page goes
+static NEXT: Mutex<u64> = Mutex::new(STACKS_START);
+static STACKS: Mutex<Vec<Stack>> = Mutex::new(Vec::new());
+
+// Must be called after the heap is initialized
+pub fn allocate(
+    name: &'static str,
+    pages: u64,
+    frame_allocator: &mut impl FrameAllocator<Size4KiB>,
+) -> Result<Stack, MapToError<Size4KiB>> {
+    let guard = without_interrupts(|| {
+        let mut next = NEXT.lock();
+        let guard = *next;
+        *next += (pages + 1) * 4096;
+        guard
+    });
+
+    let guard = VirtAddr::new(guard);
+    let first = Page::containing_address(guard) + 1;
+    // One run of frames, whatever doesn't get mapped can be given back in one go
+    let first_frame =
+        frames::allocate_contiguous(pages as usize, 1).ok_or(MapToError::FrameAllocationFailed)?;
+    memory::with_mapper(|mapper| {
+        for (i, page) in Page::range(first, first + pages).enumerate() {
+            let frame = first_frame + i as u64;
+            let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+            match unsafe { mapper.map_to(page, frame, flags, frame_allocator) } {
+                Ok(flush) => flush.flush(),
+                Err(error) => {
+                    unsafe { frames::deallocate_contiguous(frame, pages as usize - i) };
+                    return Err(error);
+                }
+            }
+        }
+        Ok(())
+    })?;
+
+    let stack = Stack {
+        name,
+        guard,
+        top: guard + (pages + 1) * 4096,
+    };
+    // Not while holding the mapper, the heap might have to grow for this
+    without_interrupts(|| STACKS.lock().push(stack));
+    Ok(stack)
+}
+
+// The stack whose guard page has this address
+// Called from the exception handler, so it gives up instead of waiting for the lock
+pub fn guarded_by(address: VirtAddr) -> Option<Stack> {
+    let stacks = STACKS.try_lock()?;
+    stacks
+        .iter()
+        .find(|stack| address >= stack.guard && address < stack.bottom())
+        .copied()
+}
+
+// Carries on running f on the stack, the current one is never returned to
+// f mustn't return either, there is nothing to return to
+pub fn switch_to<F: FnOnce()>(stack: &Stack, f: F) -> ! {
+    extern "C" fn start<F: FnOnce()>(f: *mut Option<F>) -> ! {
+        let f = unsafe { (*f).take().unwrap() };
+        f();
+        panic!("returned from the start of a stack");
+    }
+
+    // The closure stays on the old stack, which is still mapped
+    let mut f = Some(f);
+    unsafe {
+        core::arch::asm!(
+            "mov rsp, {top}",
+            // So backtraces stop here
+            "xor rbp, rbp",
+            "call {start}",
+            top = in(reg) stack.top.as_u64(),
+            start = in(reg) start::<F> as extern "C" fn(*mut Option<F>) -> ! as usize,
+            in("rdi") &mut f as *mut Option<F>,
+            options(noreturn)
+        )
+    }
+}
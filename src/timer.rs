@@ -0,0 +1,306 @@
+// Kernel timers, checked on every PIT tick
+// Callbacks run in the timer interrupt, so they have to be short and can't wait for anything
+use crate::clock;
+use alloc::boxed::Box;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+// Timers are put in the slot of their deadline, so a tick only has to look at the slots it passed
+const SLOTS: usize = 256;
+// How much time one slot covers, in nanoseconds
+const SLOT_LENGTH: u64 = 1_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimerId(u64);
+
+impl TimerId {
+    pub fn as_u64(&self) -> u64 {
+        self.0
+    }
+}
+
+struct Timer {
+    id: TimerId,
+    name: &'static str,
+    // In nanoseconds since boot
+    deadline: u64,
+    // 0 for timers that only fire once
+    period: u64,
+    fired: usize,
+    callback: Box<dyn FnMut() + Send>,
+}
+
+// What the timers command shows
+pub struct TimerInfo {
+    pub id: TimerId,
+    pub name: &'static str,
+    // Until it fires
+    pub remaining: Duration,
+    pub period: Option<Duration>,
+    pub fired: usize,
+}
+
+struct Wheel {
+    slots: [Vec<Timer>; SLOTS],
+    next_id: u64,
+    // The last slot that was checked, counting from boot
+    checked: u64,
+    // The timer whose callback is running right now, it isn't in any slot
+    running: Option<TimerId>,
+    cancel_running: bool,
+}
+
+impl Wheel {
+    fn insert(&mut self, timer: Timer) {
+        // A deadline in a slot that was already checked has to wait for the next one
+        let slot = (timer.deadline / SLOT_LENGTH).max(self.checked + 1);
+        self.slots[slot as usize % SLOTS].push(timer);
+    }
+}
+
+const EMPTY: Vec<Timer> = Vec::new();
+
+// No lazy_static, the PIT ticks before there is a heap and an empty wheel doesn't need one
+static WHEEL: Mutex<Wheel> = Mutex::new(Wheel {
+    slots: [EMPTY; SLOTS],
+    next_id: 1,
+    checked: 0,
+    running: None,
+    cancel_running: false,
+});
+
+fn add(
+    name: &'static str,
+    delay: Duration,
+    period: Duration,
+    callback: Box<dyn FnMut() + Send>,
+) -> TimerId {
+    interrupts::without_interrupts(|| {
+        let mut wheel = WHEEL.lock();
+        let id = TimerId(wheel.next_id);
+        wheel.next_id += 1;
+        wheel.insert(Timer {
+            id,
+            name,
+            deadline: clock::nanoseconds().saturating_add(clock::nanoseconds_in(delay)),
+            period: clock::nanoseconds_in(period),
+            fired: 0,
+            callback,
+        });
+        id
+    })
+}
+
+// Calls callback once after delay
+pub fn once(
+    name: &'static str,
+    delay: Duration,
+    callback: impl FnMut() + Send + 'static,
+) -> TimerId {
+    add(name, delay, Duration::from_secs(0), Box::new(callback))
+}
+
+// Calls callback every period until the timer is cancelled
+#[allow(dead_code)]
+pub fn every(
+    name: &'static str,
+    period: Duration,
+    callback: impl FnMut() + Send + 'static,
+) -> TimerId {
+    add(name, period, period, Box::new(callback))
+}
+
+// Returns false if the timer already fired or was cancelled
+pub fn cancel(id: TimerId) -> bool {
+    interrupts::without_interrupts(|| {
+        let mut wheel = WHEEL.lock();
+        // A callback can cancel its own timer
+        if wheel.running == Some(id) {
+            wheel.cancel_running = true;
+            return true;
+        }
+        for slot in wheel.slots.iter_mut() {
+            if let Some(index) = slot.iter().position(|timer| timer.id == id) {
+                slot.swap_remove(index);
+                return true;
+            }
+        }
+        false
+    })
+}
+
+// Sleeps until the time is up or stop returns true, returns false if it was stopped
+pub fn sleep_unless(duration: Duration, stop: impl Fn() -> bool) -> bool {
+    let woken = Arc::new(AtomicBool::new(false));
+    let flag = woken.clone();
+    let id = once("sleep", duration, move || {
+        flag.store(true, Ordering::Relaxed)
+    });
+
+    while !woken.load(Ordering::Relaxed) {
+        if stop() {
+            cancel(id);
+            return false;
+        }
+        interrupts::enable_and_hlt();
+    }
+    true
+}
+
+#[allow(dead_code)]
+pub fn sleep(duration: Duration) {
+    sleep_unless(duration, || false);
+}
+
+// Ordered by when they fire next
+pub fn timers() -> Vec<TimerInfo> {
+    let now = clock::nanoseconds();
+    let mut timers: Vec<TimerInfo> = interrupts::without_interrupts(|| {
+        let wheel = WHEEL.lock();
+        wheel
+            .slots
+            .iter()
+            .flatten()
+            .map(|timer| TimerInfo {
+                id: timer.id,
+                name: timer.name,
+                remaining: Duration::from_nanos(timer.deadline.saturating_sub(now)),
+                period: if timer.period > 0 {
+                    Some(Duration::from_nanos(timer.period))
+                } else {
+                    None
+                },
+                fired: timer.fired,
+            })
+            .collect()
+    });
+    timers.sort_by_key(|timer| timer.remaining);
+    timers
+}
+
+// Takes out one timer that is due, from the slots up to now
+fn next_expired(wheel: &mut Wheel, now: u64) -> Option<Timer> {
+    let current = now / SLOT_LENGTH;
+    // Skipping ahead more than a whole turn would only look at the same slots again
+    let first = (wheel.checked + 1).max(current.saturating_sub(SLOTS as u64 - 1));
+    for slot in first..=current {
+        let timers = &mut wheel.slots[slot as usize % SLOTS];
+        if let Some(index) = timers.iter().position(|timer| timer.deadline <= now) {
+            return Some(timers.swap_remove(index));
+        }
+        // Nothing left in here, no need to look again
+        // The current slot can still get timers that are due later in it
+        if slot < current {
+            wheel.checked = slot;
+        }
+    }
+    None
+}
+
+// Called from the timer interrupt with the current time
+pub fn tick(now: u64) {
+    loop {
+        // Take one expired timer out at a time, the callback may add or cancel timers
+        let mut timer = {
+            let mut wheel = WHEEL.lock();
+            match next_expired(&mut wheel, now) {
+                Some(timer) => {
+                    wheel.running = Some(timer.id);
+                    wheel.cancel_running = false;
+                    timer
+                }
+                None => return,
+            }
+        };
+
+        (timer.callback)();
+        timer.fired += 1;
+
+        let mut wheel = WHEEL.lock();
+        wheel.running = None;
+        if timer.period > 0 && !wheel.cancel_running {
+            timer.deadline = timer.deadline.saturating_add(timer.period);
+            // Don't try to catch up on ticks that were missed
+            if timer.deadline <= now {
+                timer.deadline = now.saturating_add(timer.period);
+            }
+            wheel.insert(timer);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn wheel() -> Wheel {
+        Wheel {
+            slots: [EMPTY; SLOTS],
+            next_id: 1,
+            checked: 0,
+            running: None,
+            cancel_running: false,
+        }
+    }
+
+    fn timer(id: u64, deadline: u64) -> Timer {
+        Timer {
+            id: TimerId(id),
+            name: "test",
+            deadline,
+            period: 0,
+            fired: 0,
+            callback: Box::new(|| {}),
+        }
+    }
+
+    fn expired(wheel: &mut Wheel, now: u64) -> Option<u64> {
+        next_expired(wheel, now).map(|timer| timer.id.as_u64())
+    }
+
+    #[test_case]
+    fn fires_at_the_deadline() {
+        let mut wheel = wheel();
+        wheel.insert(timer(1, 5 * SLOT_LENGTH + 10));
+        assert_eq!(expired(&mut wheel, 5 * SLOT_LENGTH), None);
+        assert_eq!(expired(&mut wheel, 5 * SLOT_LENGTH + 10), Some(1));
+        assert_eq!(expired(&mut wheel, 6 * SLOT_LENGTH), None);
+    }
+
+    #[test_case]
+    fn slots_that_were_passed_get_checked() {
+        let mut wheel = wheel();
+        wheel.insert(timer(1, 3 * SLOT_LENGTH));
+        wheel.insert(timer(2, 7 * SLOT_LENGTH));
+        let mut fired = [
+            expired(&mut wheel, 10 * SLOT_LENGTH),
+            expired(&mut wheel, 10 * SLOT_LENGTH),
+        ];
+        fired.sort();
+        assert_eq!(fired, [Some(1), Some(2)]);
+        assert_eq!(expired(&mut wheel, 10 * SLOT_LENGTH), None);
+        assert_eq!(wheel.checked, 9);
+    }
+
+    #[test_case]
+    fn late_timers_go_in_the_next_slot() {
+        let mut wheel = wheel();
+        assert_eq!(expired(&mut wheel, 4 * SLOT_LENGTH), None);
+        wheel.insert(timer(1, SLOT_LENGTH));
+        assert_eq!(expired(&mut wheel, 4 * SLOT_LENGTH), Some(1));
+    }
+
+    #[test_case]
+    fn deadlines_more_than_a_turn_away() {
+        let mut wheel = wheel();
+        let later = (SLOTS as u64 + 2) * SLOT_LENGTH;
+        wheel.insert(timer(1, later));
+        // Same slot a whole turn early
+        assert_eq!(expired(&mut wheel, 2 * SLOT_LENGTH), None);
+        assert_eq!(expired(&mut wheel, later), Some(1));
+    }
+}
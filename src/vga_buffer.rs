@@ -1,6 +1,14 @@
+use crate::console::{Console, Terminal, TerminalState};
+use crate::cp437;
+use crate::graphics::{with_font_plane, Mode, GRAPHICS};
+use crate::psf::Font;
+use alloc::collections::VecDeque;
+use alloc::string::String;
+use alloc::vec::Vec;
 use core::fmt;
+use core::sync::atomic::{AtomicUsize, Ordering};
 use lazy_static::lazy_static;
-use spin::Mutex;
+use spin::{Mutex, MutexGuard};
 use volatile::Volatile;
 use x86_64::instructions::port::Port;
 
@@ -31,6 +39,7 @@ pub enum Color {
 pub(crate) struct ColorCode(u8);
 
 impl ColorCode {
+    #[allow(dead_code)]
     pub fn new(foreground: Color, background: Color) -> ColorCode {
         ColorCode((background as u8) << 4 | (foreground as u8))
     }
@@ -47,71 +56,241 @@ pub struct ScreenChar {
 pub(crate) const BUFFER_HEIGHT: usize = 25;
 pub(crate) const BUFFER_WIDTH: usize = 80;
 
+const BLANK: ScreenChar = ScreenChar {
+    ascii_character: b' ',
+    color_code: ColorCode((Color::Black as u8) << 4 | (Color::White as u8)),
+};
+
 #[repr(transparent)]
 pub struct Buffer {
     pub chars: [[Volatile<ScreenChar>; BUFFER_WIDTH]; BUFFER_HEIGHT], // Use Volatile for futureproofing reads/writes
 }
 
+// Number of lines kept after they scroll off the top of the screen
+const SCROLLBACK_LINES: usize = 2000;
+
+type Row = [ScreenChar; BUFFER_WIDTH];
+
+struct Scrollback {
+    lines: VecDeque<Row>,
+    // How many lines the view is scrolled back, 0 means the live screen is shown
+    offset: usize,
+    // Copy of the live screen, taken when we start scrolling back
+    live: [Row; BUFFER_HEIGHT],
+}
+
 pub struct Writer {
-    pub column_position: usize,
-    color_code: ColorCode,
+    terminal: TerminalState,
     pub buffer: &'static mut Buffer,
+    // None until the heap is initialized
+    scrollback: Option<Scrollback>,
+    cursor: Cursor,
+    // Only the active console's buffer is the real VGA buffer
+    active: bool,
+    // Drawn on top of the text by flipping colors, as (row, column)
+    mouse_cursor: Option<(usize, usize)>,
+    selection: Option<((usize, usize), (usize, usize))>,
+    overlays_shown: bool,
 }
 
 impl Writer {
-    pub fn write_byte(&mut self, byte: u8) {
-        match byte {
-            b'\n' => self.new_line(),
-            byte => {
-                if self.column_position < BUFFER_WIDTH {
-                    let row = BUFFER_HEIGHT - 1;
-                    let col = self.column_position;
-
-                    let color_code = self.color_code;
-                    self.buffer.chars[row][col].write(ScreenChar {
-                        ascii_character: byte,
-                        color_code,
-                    });
-                    self.column_position += 1;
-                }
-            }
+    fn new(buffer: &'static mut Buffer, active: bool) -> Writer {
+        Writer {
+            // Output starts at the bottom of the screen
+            terminal: TerminalState::new(BUFFER_WIDTH, BUFFER_HEIGHT, BUFFER_HEIGHT - 1),
+            buffer,
+            scrollback: None,
+            cursor: Cursor::new(),
+            active,
+            mouse_cursor: None,
+            selection: None,
+            overlays_shown: true,
         }
     }
 
     pub fn write_string(&mut self, s: &str) {
-        for byte in s.bytes() {
-            match byte {
-                // Only write printable ASCII characters
-                0x20..=0x7e | b'\n' => self.write_byte(byte),
-                _ => self.write_byte(0xfe),
-            }
-        }
+        self.reset_scroll();
+        self.without_overlays(|writer| writer.write_text(s));
+        self.update_cursor();
     }
 
-    fn new_line(&mut self) {
-        // Move all the rows up
-        for row in 1..BUFFER_HEIGHT {
-            for col in 0..BUFFER_WIDTH {
-                let character = self.buffer.chars[row][col].read();
-                self.buffer.chars[row - 1][col].write(character);
-            }
+    // Keep the blinking hardware cursor where the next character will go
+    fn update_cursor(&mut self) {
+        if !self.active {
+            return;
         }
 
-        // Clear top row
-        self.clear_row(BUFFER_HEIGHT - 1);
-        self.column_position = 0;
+        let (row, col) = self.terminal.cursor();
+        let pos = row * BUFFER_WIDTH + col;
+        self.cursor.move_cursor(pos as u16);
+    }
+
+    fn color_code(&self) -> ColorCode {
+        let attributes = self.terminal.attributes();
+        ColorCode(attributes.background << 4 | attributes.foreground)
     }
 
     fn clear_row(&mut self, row: usize) {
         let blank = ScreenChar {
             ascii_character: b' ',
-            color_code: self.color_code,
+            color_code: self.color_code(),
         };
 
         for col in 0..BUFFER_WIDTH {
             self.buffer.chars[row][col].write(blank);
         }
     }
+
+    fn read_row(&self, row: usize) -> Row {
+        let mut res = [BLANK; BUFFER_WIDTH];
+        for col in 0..BUFFER_WIDTH {
+            res[col] = self.buffer.chars[row][col].read();
+        }
+        res
+    }
+
+    fn write_row(&mut self, row: usize, chars: &Row) {
+        for col in 0..BUFFER_WIDTH {
+            self.buffer.chars[row][col].write(chars[col]);
+        }
+    }
+
+    pub fn scroll_up(&mut self, lines: usize) {
+        self.without_overlays(|writer| writer.scroll_view_up(lines));
+    }
+
+    fn scroll_view_up(&mut self, lines: usize) {
+        if self.scrollback.is_none() {
+            return;
+        }
+
+        // Take a copy of the live screen so we can come back to it
+        if self.scrollback.as_ref().unwrap().offset == 0 {
+            let mut live = [[BLANK; BUFFER_WIDTH]; BUFFER_HEIGHT];
+            for row in 0..BUFFER_HEIGHT {
+                live[row] = self.read_row(row);
+            }
+            self.scrollback.as_mut().unwrap().live = live;
+        }
+
+        let scrollback = self.scrollback.as_mut().unwrap();
+        scrollback.offset = core::cmp::min(scrollback.offset + lines, scrollback.lines.len());
+        self.render_scrollback();
+    }
+
+    pub fn scroll_down(&mut self, lines: usize) {
+        if let Some(scrollback) = self.scrollback.as_mut() {
+            if scrollback.offset == 0 {
+                return;
+            }
+            scrollback.offset = scrollback.offset.saturating_sub(lines);
+            self.without_overlays(|writer| writer.render_scrollback());
+        }
+    }
+
+    // Go back to the live screen if we are scrolled back
+    pub fn reset_scroll(&mut self) {
+        if let Some(scrollback) = self.scrollback.as_ref() {
+            if scrollback.offset != 0 {
+                self.scroll_down(SCROLLBACK_LINES);
+            }
+        }
+    }
+
+    fn render_scrollback(&mut self) {
+        let scrollback = match self.scrollback.take() {
+            Some(scrollback) => scrollback,
+            None => return,
+        };
+
+        // The view is a window over the history followed by the live screen
+        let top = scrollback.lines.len() - scrollback.offset;
+        for row in 0..BUFFER_HEIGHT {
+            let line = top + row;
+            if line < scrollback.lines.len() {
+                self.write_row(row, &scrollback.lines[line]);
+            } else {
+                self.write_row(row, &scrollback.live[line - scrollback.lines.len()]);
+            }
+        }
+
+        self.scrollback = Some(scrollback);
+    }
+
+    // The selection goes away whenever what's on screen changes
+    fn without_overlays<F: FnOnce(&mut Writer)>(&mut self, f: F) {
+        let shown = self.overlays_shown;
+        if shown {
+            self.toggle_overlays();
+            self.overlays_shown = false;
+        }
+        self.selection = None;
+
+        f(self);
+
+        if shown {
+            self.toggle_overlays();
+            self.overlays_shown = true;
+        }
+    }
+
+    fn toggle_overlays(&mut self) {
+        // Swapping the colors and inverting them can be undone in any order, so the
+        // mouse cursor stays visible on top of the selection
+        if let Some((row, col)) = self.mouse_cursor {
+            self.recolor(row, col, |color| color.rotate_left(4));
+        }
+        if let Some((start, end)) = self.selection {
+            for pos in start.0 * BUFFER_WIDTH + start.1..=end.0 * BUFFER_WIDTH + end.1 {
+                self.recolor(pos / BUFFER_WIDTH, pos % BUFFER_WIDTH, |color| color ^ 0x77);
+            }
+        }
+    }
+
+    fn recolor<F: Fn(u8) -> u8>(&mut self, row: usize, col: usize, f: F) {
+        let mut screen_char = self.buffer.chars[row][col].read();
+        screen_char.color_code = ColorCode(f(screen_char.color_code.0));
+        self.buffer.chars[row][col].write(screen_char);
+    }
+
+    pub fn set_mouse_cursor(&mut self, position: Option<(usize, usize)>) {
+        self.toggle_overlays();
+        self.mouse_cursor = position;
+        self.toggle_overlays();
+    }
+
+    // Start and end can be given in any order, both are included
+    pub fn set_selection(&mut self, selection: Option<((usize, usize), (usize, usize))>) {
+        self.toggle_overlays();
+        self.selection = selection.map(|(a, b)| (core::cmp::min(a, b), core::cmp::max(a, b)));
+        self.toggle_overlays();
+    }
+
+    // One line per row, without trailing spaces
+    pub fn selected_text(&self) -> String {
+        let mut text = String::new();
+        let (start, end) = match self.selection {
+            Some(selection) => selection,
+            None => return text,
+        };
+
+        for row in start.0..=end.0 {
+            let first = if row == start.0 { start.1 } else { 0 };
+            let last = if row == end.0 {
+                end.1
+            } else {
+                BUFFER_WIDTH - 1
+            };
+            let line: String = (first..=last)
+                .map(|col| cp437::decode(self.buffer.chars[row][col].read().ascii_character))
+                .collect();
+            text.push_str(line.trim_end());
+            if row != end.0 {
+                text.push('\n');
+            }
+        }
+        text
+    }
 }
 
 impl fmt::Write for Writer {
@@ -121,51 +300,173 @@ impl fmt::Write for Writer {
     }
 }
 
+impl Terminal for Writer {
+    fn terminal(&mut self) -> &mut TerminalState {
+        &mut self.terminal
+    }
+
+    fn put(&mut self, row: usize, column: usize, glyph: u8) {
+        let color_code = self.color_code();
+        self.buffer.chars[row][column].write(ScreenChar {
+            ascii_character: glyph,
+            color_code,
+        });
+    }
+
+    fn scroll(&mut self) {
+        // Save the top row before it gets overwritten
+        if self.scrollback.is_some() {
+            let top = self.read_row(0);
+            let scrollback = self.scrollback.as_mut().unwrap();
+            if scrollback.lines.len() == SCROLLBACK_LINES {
+                scrollback.lines.pop_front();
+            }
+            scrollback.lines.push_back(top);
+        }
+
+        // Move all the rows up
+        for row in 1..BUFFER_HEIGHT {
+            for col in 0..BUFFER_WIDTH {
+                let character = self.buffer.chars[row][col].read();
+                self.buffer.chars[row - 1][col].write(character);
+            }
+        }
+
+        self.clear_row(BUFFER_HEIGHT - 1);
+    }
+}
+
+impl Console for Writer {
+    fn write_string(&mut self, s: &str) {
+        Writer::write_string(self, s);
+    }
+
+    fn move_back(&mut self, characters: usize) {
+        self.back(characters);
+        self.update_cursor();
+    }
+
+    fn change_color(&mut self, foreground: Color, background: Color) {
+        self.terminal.set_colors(foreground, background);
+    }
+
+    fn size(&self) -> (usize, usize) {
+        self.terminal.size()
+    }
+}
+
+pub const CONSOLES: usize = 4;
+
+// Screens of the consoles that aren't being shown
+static mut OFFSCREEN: [[[ScreenChar; BUFFER_WIDTH]; BUFFER_HEIGHT]; CONSOLES - 1] =
+    [[[BLANK; BUFFER_WIDTH]; BUFFER_HEIGHT]; CONSOLES - 1];
+
+static ACTIVE: AtomicUsize = AtomicUsize::new(0);
+
+fn offscreen(index: usize) -> &'static mut Buffer {
+    // Buffer is repr(transparent) over ScreenChars, and every offscreen buffer is only handed out once
+    unsafe {
+        let screens = core::ptr::addr_of_mut!(OFFSCREEN) as *mut Buffer;
+        &mut *screens.add(index)
+    }
+}
+
 lazy_static! {
-    pub static ref WRITER: Mutex<Writer> = Mutex::new(Writer {
-        column_position: 0,
-        color_code: ColorCode::new(Color::White, Color::Black),
-        buffer: unsafe { &mut *(0xb8000 as *mut Buffer) }
-    });
+    static ref WRITERS: [Mutex<Writer>; CONSOLES] = [
+        Mutex::new(Writer::new(unsafe { &mut *(0xb8000 as *mut Buffer) }, true)),
+        Mutex::new(Writer::new(offscreen(0), false)),
+        Mutex::new(Writer::new(offscreen(1), false)),
+        Mutex::new(Writer::new(offscreen(2), false)),
+    ];
 }
 
-#[macro_export]
-macro_rules! print {
-    ($($arg:tt)*) => ($crate::vga_buffer::_print(format_args!($($arg)*)));
+// The console that is currently on screen
+pub fn active() -> usize {
+    ACTIVE.load(Ordering::Relaxed)
 }
 
-#[macro_export]
-macro_rules! println {
-    () => ($crate::print!("\n"));
-    ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
+// Writer of the console that is currently on screen
+pub fn writer() -> MutexGuard<'static, Writer> {
+    writer_of(active())
 }
 
-#[doc(hidden)]
-pub fn _print(args: fmt::Arguments) {
-    use core::fmt::Write;
+pub fn writer_of(console: usize) -> MutexGuard<'static, Writer> {
+    WRITERS[console].lock()
+}
+
+pub fn switch_console(target: usize) {
     use x86_64::instructions::interrupts;
 
-    // Turn off interrupts to avoid a deadlock
+    let current = active();
+    // The framebuffer console is a single screen, and closing it puts the text back in the active one
+    if target == current || target >= CONSOLES || crate::console::framebuffer_enabled() {
+        return;
+    }
+    // In graphics mode the screen memory holds pixels, not the active console's text
+    // try_lock, because this runs in the keyboard interrupt and a command could be drawing
+    let text_mode = GRAPHICS
+        .try_lock()
+        .map_or(false, |graphics| graphics.mode() == Mode::Text);
+    if !text_mode {
+        return;
+    }
+
     interrupts::without_interrupts(|| {
-        WRITER.lock().write_fmt(args).unwrap();
+        // Always lock in the same order so we can't deadlock
+        let mut first = WRITERS[core::cmp::min(current, target)].lock();
+        let mut second = WRITERS[core::cmp::max(current, target)].lock();
+        let (from, to) = if current < target {
+            (&mut *first, &mut *second)
+        } else {
+            (&mut *second, &mut *first)
+        };
+
+        from.reset_scroll();
+        let mouse_cursor = from.mouse_cursor;
+        from.set_mouse_cursor(None);
+        from.set_selection(None);
+
+        // Swap what's on screen with the target's offscreen buffer, then swap which writer owns which
+        for row in 0..BUFFER_HEIGHT {
+            for col in 0..BUFFER_WIDTH {
+                let shown = from.buffer.chars[row][col].read();
+                let hidden = to.buffer.chars[row][col].read();
+                from.buffer.chars[row][col].write(hidden);
+                to.buffer.chars[row][col].write(shown);
+            }
+        }
+        core::mem::swap(&mut from.buffer, &mut to.buffer);
+
+        from.active = false;
+        to.active = true;
+        ACTIVE.store(target, Ordering::Relaxed);
+        to.set_mouse_cursor(mouse_cursor);
+        to.update_cursor();
     });
 }
 
-pub fn backspace() {
-    let mut writer = WRITER.lock();
-    let row = BUFFER_HEIGHT - 1;
-    let col = writer.column_position;
-    let color_code = writer.color_code;
-    // Barrier for prompt
-    if col != 4 {
-        writer.buffer.chars[row][col - 1].write(ScreenChar {
-            ascii_character: b' ',
-            color_code,
+// Must be called after the heap is initialized
+pub fn init_scrollback() {
+    for writer in WRITERS.iter() {
+        let mut writer = writer.lock();
+        writer.scrollback = Some(Scrollback {
+            lines: VecDeque::new(),
+            offset: 0,
+            live: [[BLANK; BUFFER_WIDTH]; BUFFER_HEIGHT],
         });
-        writer.column_position -= 1;
     }
 }
 
+// Print to a console even if it isn't the one on screen
+pub fn print_to(console: usize, args: fmt::Arguments) {
+    use core::fmt::Write;
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        WRITERS[console].lock().write_fmt(args).unwrap();
+    });
+}
+
 pub struct Cursor {
     port_low: Port<u8>,
     port_high: Port<u8>,
@@ -189,18 +490,78 @@ impl Cursor {
     }
 }
 
+// Glyphs are 32 bytes apart in plane 2, but only the first 16 rows are shown
+const FONT_GLYPHS: usize = 256;
+const FONT_GLYPH_STRIDE: usize = 32;
+const FONT_HEIGHT: usize = 16;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FontError {
+    InvalidFormat,
+    // Only fonts 8 pixels wide and up to 16 pixels high fit in text mode
+    UnsupportedSize,
+}
+
 lazy_static! {
-    pub static ref CURSOR: Mutex<Cursor> = Mutex::new(Cursor::new());
+    // The font the BIOS loaded, saved before the first custom font replaces it
+    static ref BIOS_FONT: Mutex<Option<Vec<u8>>> = Mutex::new(None);
+}
+
+fn write_font(glyphs: &[u8]) {
+    with_font_plane(|plane| {
+        for (i, &byte) in glyphs.iter().enumerate() {
+            unsafe { plane.add(i).write_volatile(byte) };
+        }
+    });
 }
 
-pub fn move_cursor(x: u16, y: u16) {
-    let pos: u16 = y * (BUFFER_WIDTH as u16) + x;
-    let mut cursor = CURSOR.lock();
-    cursor.move_cursor(pos);
+// Uploads the first 256 glyphs of font, shorter glyphs are padded at the bottom
+pub fn load_font(font: &Font) -> Result<(), FontError> {
+    use x86_64::instructions::interrupts;
+
+    if font.width != 8 || font.height > FONT_HEIGHT {
+        return Err(FontError::UnsupportedSize);
+    }
+
+    let mut glyphs = Vec::with_capacity(FONT_GLYPHS * FONT_GLYPH_STRIDE);
+    for glyph in 0..FONT_GLYPHS {
+        let bitmap = font.glyph(glyph);
+        for row in 0..FONT_GLYPH_STRIDE {
+            glyphs.push(bitmap.get(row).copied().unwrap_or(0));
+        }
+    }
+
+    interrupts::without_interrupts(|| {
+        let mut bios_font = BIOS_FONT.lock();
+        if bios_font.is_none() {
+            let mut saved = Vec::with_capacity(FONT_GLYPHS * FONT_GLYPH_STRIDE);
+            with_font_plane(|plane| {
+                for i in 0..FONT_GLYPHS * FONT_GLYPH_STRIDE {
+                    saved.push(unsafe { plane.add(i).read_volatile() });
+                }
+            });
+            *bios_font = Some(saved);
+        }
+
+        write_font(&glyphs);
+    });
+
+    Ok(())
+}
+
+// Loads a PSF file, version 1 or 2
+pub fn load_psf(data: &[u8]) -> Result<(), FontError> {
+    let font = Font::parse(data).ok_or(FontError::InvalidFormat)?;
+    load_font(&font)
 }
 
-pub fn change_color(foreground: Color, background: Color) {
-    let mut writer = WRITER.lock();
-    let color = ColorCode::new(foreground, background);
-    writer.color_code = color;
+// Goes back to the font we booted with
+pub fn restore_bios_font() {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        if let Some(glyphs) = BIOS_FONT.lock().as_ref() {
+            write_font(glyphs);
+        }
+    });
 }
@@ -0,0 +1,5 @@
+#!/bin/sh
+# cargo run and cargo test go through here, the symbols get filled in before the boot image is made
+set -e
+python3 "$(dirname "$0")/symbols.py" "$1"
+exec bootimage runner "$@"
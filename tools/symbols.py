@@ -0,0 +1,129 @@
+#!/usr/bin/env python3
+# Fills the kernel's .ksyms section with its own function symbols, so backtraces have names
+# The layout has to match src/backtrace.rs
+#
+# Usage: tools/symbols.py <kernel ELF>
+import re
+import struct
+import sys
+
+SECTION = b".ksyms"
+MAGIC = b"KSYM"
+HEADER_SIZE = 8
+ENTRY_SIZE = 16
+# Long generic names aren't worth the space
+MAX_NAME = 160
+
+STT_FUNC = 2
+SHT_SYMTAB = 2
+
+ESCAPES = {
+    "$SP$": "@",
+    "$BP$": "*",
+    "$RF$": "&",
+    "$LT$": "<",
+    "$GT$": ">",
+    "$LP$": "(",
+    "$RP$": ")",
+    "$C$": ",",
+}
+
+
+# Only the legacy Rust mangling, anything else keeps its raw name
+def demangle(name):
+    if not name.startswith("_ZN") or not name.endswith("E"):
+        return name
+    rest = name[3:-1]
+    parts = []
+    while rest:
+        match = re.match(r"(\d+)", rest)
+        if not match:
+            return name
+        length = int(match.group(1))
+        start = len(match.group(1))
+        parts.append(rest[start : start + length])
+        rest = rest[start + length :]
+    # The last part is a hash
+    if parts and re.fullmatch(r"h[0-9a-f]{16}", parts[-1]):
+        parts.pop()
+
+    def unescape(part):
+        if part.startswith("_$"):
+            part = part[1:]
+        for escape, replacement in ESCAPES.items():
+            part = part.replace(escape, replacement)
+        part = re.sub(r"\$u([0-9a-f]+)\$", lambda m: chr(int(m.group(1), 16)), part)
+        return part.replace("..", "::")
+
+    return "::".join(unescape(part) for part in parts)
+
+
+def sections(elf):
+    (shoff,) = struct.unpack_from("<Q", elf, 0x28)
+    shentsize, shnum, shstrndx = struct.unpack_from("<HHH", elf, 0x3A)
+    headers = [
+        struct.unpack_from("<IIQQQQIIQQ", elf, shoff + i * shentsize) for i in range(shnum)
+    ]
+    names = headers[shstrndx][4]
+
+    def name(offset):
+        return elf[names + offset : elf.index(b"\0", names + offset)]
+
+    return [(name(header[0]), header) for header in headers]
+
+
+def function_symbols(elf, all_sections):
+    symbols = {}
+    for _, header in all_sections:
+        if header[1] != SHT_SYMTAB:
+            continue
+        offset, size, link, entry_size = header[4], header[5], header[6], header[9]
+        strings = all_sections[link][1][4]
+        for i in range(size // entry_size):
+            name, info, _, _, value, length = struct.unpack_from(
+                "<IBBHQQ", elf, offset + i * entry_size
+            )
+            if info & 0xF != STT_FUNC or value == 0:
+                continue
+            raw = elf[strings + name : elf.index(b"\0", strings + name)].decode()
+            symbols.setdefault(value, (length, demangle(raw)[:MAX_NAME]))
+    return sorted(symbols.items())
+
+
+def build_table(symbols):
+    entries = bytearray()
+    strings = bytearray()
+    for address, (length, name) in symbols:
+        entries += struct.pack("<QII", address, min(length, 0xFFFFFFFF), len(strings))
+        strings += name.encode() + b"\0"
+    return MAGIC + struct.pack("<I", len(symbols)) + entries + strings
+
+
+def main():
+    if len(sys.argv) != 2:
+        sys.exit("usage: symbols.py <kernel ELF>")
+    path = sys.argv[1]
+    with open(path, "rb") as f:
+        elf = bytearray(f.read())
+
+    all_sections = sections(elf)
+    section = next((header for name, header in all_sections if name == SECTION), None)
+    if section is None:
+        sys.exit("{}: no {} section".format(path, SECTION.decode()))
+    offset, size = section[4], section[5]
+
+    table = build_table(function_symbols(elf, all_sections))
+    if len(table) > size:
+        sys.exit(
+            "symbol table is {} bytes, but there are only {}, make SYMBOLS_SIZE bigger".format(
+                len(table), size
+            )
+        )
+    elf[offset : offset + size] = table + bytes(size - len(table))
+
+    with open(path, "wb") as f:
+        f.write(elf)
+
+
+if __name__ == "__main__":
+    main()